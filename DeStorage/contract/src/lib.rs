@@ -1,13 +1,115 @@
+// Contract methods are flat JSON entry points, so long argument lists are expected.
+#![allow(clippy::too_many_arguments)]
+
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{UnorderedMap, UnorderedSet};
+use near_sdk::collections::{UnorderedMap, UnorderedSet, Vector};
+use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::{env, near_bindgen, setup_alloc};
+use near_sdk::serde_json::{json, Value};
+use near_sdk::{env, near_bindgen, setup_alloc, Balance, Promise, StorageUsage};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
 use std::option::Option::{None, Some};
 use std::vec::Vec;
 
 setup_alloc!();
 
-#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+const MAX_NODES_PER_CALL: usize = 50;
+const MAX_NAME_LENGTH: usize = 256;
+const MAX_CID_LENGTH: usize = 128;
+const DEFAULT_QUOTA: u64 = 1 << 30; // 1 GiB
+const MAX_VIEW_NODES: usize = 500;
+const MAX_FILE_VERSIONS: usize = 10;
+const MAX_ANCESTOR_HOPS: usize = 1000;
+const MAX_DESCRIPTION_LENGTH: usize = 1024;
+const MAX_TAGS: usize = 20;
+const MAX_BATCH_VIEW: usize = 100;
+const DEFAULT_MAX_DEPTH: u32 = 64;
+const DEFAULT_MAX_FILES_PER_FOLDER: u32 = 10_000;
+const MAX_ACTIVITY_ENTRIES: u64 = 100;
+const MAX_REQUEST_IDS: usize = 50;
+// Byte length assumed for ids, account ids and other strings a cost estimate
+// isn't told about. Account ids are at most 64 bytes.
+const ESTIMATED_STRING_LENGTH: usize = 64;
+// Storage NEAR charges for every key-value record on top of its bytes.
+const STORAGE_RECORD_OVERHEAD: usize = 40;
+const CONTRACT_VERSION: u32 = 1;
+const VERSION: &str = "1.0.0";
+const EVENT_STANDARD: &str = "destorage";
+const EVENT_VERSION: &str = "1.0.0";
+
+// Enums that are stored and sent over JSON as the small integers the contract
+// has always used, so existing records keep deserializing.
+macro_rules! numeric_enum {
+    ($name:ident { $($variant:ident = $value:expr),+ $(,)? }) => {
+        #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+        #[serde(crate = "near_sdk::serde", try_from = "u8", into = "u8")]
+        pub enum $name {
+            $($variant),+
+        }
+
+        impl From<$name> for u8 {
+            fn from(value: $name) -> u8 {
+                match value {
+                    $($name::$variant => $value),+
+                }
+            }
+        }
+
+        impl TryFrom<u8> for $name {
+            type Error = String;
+
+            fn try_from(value: u8) -> Result<Self, Self::Error> {
+                match value {
+                    $($value => Ok($name::$variant),)+
+                    _ => Err(format!("invalid {} value {}", stringify!($name), value)),
+                }
+            }
+        }
+
+        impl BorshSerialize for $name {
+            fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+                BorshSerialize::serialize(&u8::from(*self), writer)
+            }
+        }
+
+        impl BorshDeserialize for $name {
+            fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+                $name::try_from(<u8 as BorshDeserialize>::deserialize(buf)?)
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+            }
+        }
+    };
+}
+
+// 1 read, 2 write, 3 manage: each level includes the ones below it, and manage
+// also allows sharing the tree further.
+numeric_enum!(Permission { Read = 1, Write = 2, Manage = 3 });
+numeric_enum!(DocType { File = 1, Folder = 2 });
+numeric_enum!(FolderKind { Common = 1, Shared = 2 });
+
+impl Permission {
+    pub fn can_write(self) -> bool {
+        self != Permission::Read
+    }
+
+    pub fn can_manage(self) -> bool {
+        self == Permission::Manage
+    }
+}
+
+// Returned to clients that need to tell failures apart; broken invariants
+// still panic.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum DsError {
+    NotFound,
+    NotShared,
+    Expired,
+    InsufficientPermission,
+}
+
+#[derive(Serialize, Deserialize, BorshSerialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct File {
     cid: String,
@@ -18,36 +120,492 @@ pub struct File {
     update_by: String,
     created_at: u64,
     created_by: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    versions: Vec<FileVersion>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    last_accessed: Option<u64>,
+    // Block timestamp of the creation; unlike `created_at` the client can't
+    // set it. 0 for files stored before it was recorded.
+    #[serde(default)]
+    block_time: u64,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    mime: Option<String>,
+    #[serde(default)]
+    thumbnail_cid: Option<String>,
+}
+
+// Files stored before the fields below `created_by` existed end right after it.
+impl BorshDeserialize for File {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        Ok(Self {
+            cid: BorshDeserialize::deserialize(buf)?,
+            name: BorshDeserialize::deserialize(buf)?,
+            encrypted_password: BorshDeserialize::deserialize(buf)?,
+            file_type: BorshDeserialize::deserialize(buf)?,
+            last_update: BorshDeserialize::deserialize(buf)?,
+            update_by: BorshDeserialize::deserialize(buf)?,
+            created_at: BorshDeserialize::deserialize(buf)?,
+            created_by: BorshDeserialize::deserialize(buf)?,
+            size: deserialize_or_default(buf)?,
+            versions: deserialize_or_default(buf)?,
+            tags: deserialize_or_default(buf)?,
+            last_accessed: deserialize_or_default(buf)?,
+            block_time: deserialize_or_default(buf)?,
+            description: deserialize_or_default(buf)?,
+            mime: deserialize_or_default(buf)?,
+            thumbnail_cid: deserialize_or_default(buf)?,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
 #[serde(crate = "near_sdk::serde")]
+pub struct FileVersion {
+    cid: String,
+    updated_at: u64,
+    updated_by: String,
+}
+
+#[derive(Serialize, Deserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
 pub struct FolderV2 {
     name: String,
     files: Vec<String>,
     parent: String,
     children: Vec<String>,
-    folder_type: Option<u8>, // 1 is common folder, 2 is shared folder
+    folder_type: Option<FolderKind>,
     folder_password: Option<String>,
     created_by: String,
     created_at: u64,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    last_update: u64,
+    #[serde(default)]
+    update_by: String,
+    // Co-owners; only kept on top-level folders, the roots `find_root` returns.
+    #[serde(default)]
+    owners: Vec<String>,
+    // Block timestamp of the creation; unlike `created_at` the client can't
+    // set it. 0 for folders stored before it was recorded.
+    #[serde(default)]
+    block_time: u64,
 }
 
-#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+// Folders stored before the fields below `created_at` existed end right after
+// it; their last update falls back to their creation.
+impl BorshDeserialize for FolderV2 {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        let name = BorshDeserialize::deserialize(buf)?;
+        let files = BorshDeserialize::deserialize(buf)?;
+        let parent = BorshDeserialize::deserialize(buf)?;
+        let children = BorshDeserialize::deserialize(buf)?;
+        // The first version stored any u8 here; a value it didn't know means no
+        // type.
+        let folder_type: Option<u8> = BorshDeserialize::deserialize(buf)?;
+        let folder_type = folder_type.and_then(|value| FolderKind::try_from(value).ok());
+        let folder_password = BorshDeserialize::deserialize(buf)?;
+        let created_by: String = BorshDeserialize::deserialize(buf)?;
+        let created_at: u64 = BorshDeserialize::deserialize(buf)?;
+        let tags = deserialize_or_default(buf)?;
+        let (last_update, update_by) = if buf.is_empty() {
+            (created_at, created_by.clone())
+        } else {
+            (
+                BorshDeserialize::deserialize(buf)?,
+                BorshDeserialize::deserialize(buf)?,
+            )
+        };
+        // The account a root sits under owns it anyway, and `created_by` may be
+        // a collaborator, so older folders start without co-owners.
+        let owners = deserialize_or_default(buf)?;
+        let block_time = deserialize_or_default(buf)?;
+        Ok(Self {
+            name,
+            files,
+            parent,
+            children,
+            folder_type,
+            folder_password,
+            created_by,
+            created_at,
+            tags,
+            last_update,
+            update_by,
+            owners,
+            block_time,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, BorshSerialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct User {
     public_key: String,
     encrypted_token: String,
+    // Id of the account's root folder; empty when the root is keyed by the
+    // account id, as every root created before custom ids was.
+    #[serde(default)]
+    root_id: String,
 }
 
-#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+// Users stored before `root_id` existed end right after the token.
+impl BorshDeserialize for User {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        Ok(Self {
+            public_key: BorshDeserialize::deserialize(buf)?,
+            encrypted_token: BorshDeserialize::deserialize(buf)?,
+            root_id: deserialize_or_default(buf)?,
+        })
+    }
+}
+
+// What any caller may see of a user; the encrypted token is only returned by
+// `get_own_user`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PublicUser {
+    public_key: String,
+}
+
+impl From<User> for PublicUser {
+    fn from(user: User) -> Self {
+        Self {
+            public_key: user.public_key,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, BorshSerialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct ShareDoc {
     doc_id: String,
     share_password: String,
-    permission: u8,
+    permission: Permission,
+    created_at: u64,
+    doc_type: DocType,
+    #[serde(default)]
+    expires_at: Option<u64>,
+    #[serde(default)]
+    shared_with: String,
+    // The owner or manager who created the share; empty for older shares,
+    // which only owners could create.
+    #[serde(default)]
+    shared_by: String,
+}
+
+impl ShareDoc {
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= env::block_timestamp(),
+            None => false,
+        }
+    }
+}
+
+// Shares stored before `expires_at`, `shared_with` and `shared_by` existed end
+// right after `doc_type`. Those took any u8 as permission and only 2 allowed
+// writing, so every other value reads as `Read`.
+impl BorshDeserialize for ShareDoc {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        let doc_id = BorshDeserialize::deserialize(buf)?;
+        let share_password = BorshDeserialize::deserialize(buf)?;
+        let permission: u8 = BorshDeserialize::deserialize(buf)?;
+        let created_at = BorshDeserialize::deserialize(buf)?;
+        let doc_type = BorshDeserialize::deserialize(buf)?;
+        let permission = if buf.is_empty() {
+            if permission == u8::from(Permission::Write) {
+                Permission::Write
+            } else {
+                Permission::Read
+            }
+        } else {
+            Permission::try_from(permission)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?
+        };
+        Ok(Self {
+            doc_id,
+            share_password,
+            permission,
+            created_at,
+            doc_type,
+            expires_at: deserialize_or_default(buf)?,
+            shared_with: deserialize_or_default(buf)?,
+            shared_by: deserialize_or_default(buf)?,
+        })
+    }
+}
+
+// Logs a NEP-297 event; the signer is added to `data` so callers only pass ids.
+fn emit_event(event: &str, mut data: Value) {
+    data["signer"] = json!(env::signer_account_id());
+    let event = json!({
+        "standard": EVENT_STANDARD,
+        "version": EVENT_VERSION,
+        "event": event,
+        "data": [data],
+    });
+    env::log(format!("EVENT_JSON:{}", event).as_bytes());
+}
+
+// Each nested set is stored under its map's prefix plus a hash of the key, so
+// no two accounts ever share a set's storage.
+fn insert_into_set(
+    map: &mut UnorderedMap<String, UnorderedSet<String>>,
+    map_prefix: &[u8],
+    key: &String,
+    value: &String,
+) {
+    let mut set = map.get(key).unwrap_or_else(|| {
+        let mut prefix = map_prefix.to_vec();
+        prefix.extend(env::sha256(key.as_bytes()));
+        UnorderedSet::new(prefix)
+    });
+    set.insert(value);
+    map.insert(key, &set);
+}
+
+// Drops the set from the map once it is empty to free its storage.
+fn remove_from_set(
+    map: &mut UnorderedMap<String, UnorderedSet<String>>,
+    key: &String,
+    value: &String,
+) {
+    if let Some(mut set) = map.get(key) {
+        set.remove(value);
+        if set.is_empty() {
+            map.remove(key);
+        } else {
+            map.insert(key, &set);
+        }
+    }
+}
+
+fn share_permission(share_doc: Option<ShareDoc>) -> Result<Permission, DsError> {
+    match share_doc {
+        Some(share_doc) if share_doc.is_expired() => Err(DsError::Expired),
+        Some(share_doc) => Ok(share_doc.permission),
+        None => Err(DsError::NotShared),
+    }
+}
+
+// Hashing the Borsh encoding keeps ids unambiguous even when account or doc
+// ids contain underscores.
+fn share_doc_id(owner_id: &str, shared_with: &str, doc_id: &str) -> String {
+    let parts = (
+        owner_id.to_string(),
+        shared_with.to_string(),
+        doc_id.to_string(),
+    );
+    to_hex(&env::sha256(&parts.try_to_vec().unwrap()))
+}
+
+fn is_hashed_share_id(share_doc_id: &str) -> bool {
+    share_doc_id.len() == 64 && share_doc_id.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+// Shares created before ids were hashed are still stored under this format.
+fn legacy_share_doc_id(owner_id: &str, shared_with: &str, doc_id: &str) -> String {
+    format!("{}_{}_{}", owner_id, shared_with, doc_id)
+}
+
+fn share_recipient(share_id: &str, share_doc: &ShareDoc, owner_id: &str) -> Option<String> {
+    if !share_doc.shared_with.is_empty() {
+        return Some(share_doc.shared_with.clone());
+    }
+    share_id
+        .strip_prefix(&format!("{}_", owner_id))?
+        .strip_suffix(&format!("_{}", share_doc.doc_id))
+        .map(|shared_with| shared_with.to_string())
+}
+
+// Clients may pass 0 as a timestamp to have the block timestamp used instead.
+fn or_block_time(at: u64) -> u64 {
+    if at == 0 {
+        env::block_timestamp()
+    } else {
+        at
+    }
+}
+
+// Views never hand out a folder's password; clients check a candidate with
+// `verify_folder_password` instead.
+fn redact_folder(mut folder: FolderV2) -> FolderV2 {
+    folder.folder_password = None;
+    folder
+}
+
+// Share passwords are stored as a digest behind this prefix. Shares created
+// before that still hold the password itself.
+const HASHED_PASSWORD_PREFIX: &str = "sha256:";
+
+fn hash_share_password(password: &str) -> String {
+    format!(
+        "{}{}",
+        HASHED_PASSWORD_PREFIX,
+        to_hex(&env::sha256(password.as_bytes()))
+    )
+}
+
+fn share_password_matches(stored: &str, candidate: &str) -> bool {
+    match stored.strip_prefix(HASHED_PASSWORD_PREFIX) {
+        Some(digest) => digest == to_hex(&env::sha256(candidate.as_bytes())),
+        None => env::sha256(stored.as_bytes()) == env::sha256(candidate.as_bytes()),
+    }
+}
+
+// Like folders, shares are never returned with their password.
+fn redact_share(mut share_doc: ShareDoc) -> ShareDoc {
+    share_doc.share_password = String::new();
+    share_doc
+}
+
+fn touch_folder(folder: &mut FolderV2) {
+    folder.last_update = env::block_timestamp();
+    folder.update_by = env::signer_account_id();
+}
+
+fn push_tag(tags: &mut Vec<String>, tag: String) {
+    if !tags.contains(&tag) {
+        assert!(
+            tags.len() < MAX_TAGS,
+            "Can't add more than {} tags",
+            MAX_TAGS
+        );
+        tags.push(tag);
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Bytes an UnorderedMap entry takes: the key's index, the key and the value,
+// each stored as its own record under a prefixed key.
+fn map_entry_len(key_len: usize, value_len: usize) -> usize {
+    3 * STORAGE_RECORD_OVERHEAD + (2 + key_len + 8) + (2 + 8 + key_len) + (2 + 8 + value_len)
+}
+
+fn deserialize_or_default<T: BorshDeserialize + Default>(buf: &mut &[u8]) -> std::io::Result<T> {
+    if buf.is_empty() {
+        Ok(T::default())
+    } else {
+        T::deserialize(buf)
+    }
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PublicLink {
+    owner: String,
+    share: ShareDoc,
+}
+
+// A folder waiting for `to` to take it over. `accepted` is set once the move
+// started, so the offer can no longer be cancelled or replaced.
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct GiftOffer {
+    from: String,
+    to: String,
+    accepted: bool,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountStats {
+    file_count: u64,
+    folder_count: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FolderSummary {
+    name: String,
+    parent: String,
+    file_count: u64,
+    child_count: u64,
+    folder_type: Option<FolderKind>,
     created_at: u64,
-    doc_type: u8, // 1 is file, 2 is folder
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HomeView {
+    user: Option<PublicUser>,
+    root: Option<FolderV2>,
+    children: Vec<(String, FolderSummary)>,
+    files: Vec<(String, File)>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FolderInput {
+    id: String,
+    name: String,
+    parent: String,
+    password: Option<String>,
+    folder_type: Option<FolderKind>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FileInput {
+    file_id: String,
+    cid: String,
+    name: String,
+    encrypted_password: Option<String>,
+    file_type: String,
+    size: u64,
+    #[serde(default)]
+    mime: Option<String>,
+    #[serde(default)]
+    thumbnail_cid: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ShareTarget {
+    account: String,
+    password: String,
+    permission: Permission,
+}
+
+// (share_doc_id, share, shared file, shared folder)
+pub type SharedDocDetail = (String, Option<ShareDoc>, Option<File>, Option<FolderV2>);
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TrashedItem {
+    id: String,
+    item_type: DocType,
+    parent: String,
+    owner: String,
+    deleted_at: u64,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ActivityEntry {
+    action: String,
+    target: String,
+    timestamp: u64,
+    actor: String,
+}
+
+// A ring of the last MAX_ACTIVITY_ENTRIES entries; `written` counts every entry
+// ever recorded, so the oldest one sits at `written % MAX_ACTIVITY_ENTRIES`
+// once the ring is full.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ActivityLog {
+    entries: Vector<ActivityEntry>,
+    written: u64,
 }
 
 #[near_bindgen]
@@ -58,6 +616,39 @@ pub struct Contract {
     files: UnorderedMap<String, File>,
     shared_docs: UnorderedMap<String, ShareDoc>,
     shared_doc_of_user: UnorderedMap<String, UnorderedSet<String>>,
+    storage_used: UnorderedMap<String, StorageUsage>,
+    quotas: UnorderedMap<String, u64>,
+    usage: UnorderedMap<String, u64>,
+    default_quota: u64,
+    owner_id: String,
+    paused: bool,
+    trashed: UnorderedMap<String, TrashedItem>,
+    trash_of_user: UnorderedMap<String, UnorderedSet<String>>,
+    shared_by_user: UnorderedMap<String, UnorderedSet<String>>,
+    public_links: UnorderedMap<String, PublicLink>,
+    favorites: UnorderedMap<String, UnorderedSet<String>>,
+    version: u32,
+    stats: UnorderedMap<String, AccountStats>,
+    max_depth: u32,
+    share_counts: UnorderedMap<String, u64>,
+    max_files_per_folder: u32,
+    activity: UnorderedMap<String, ActivityLog>,
+    file_parent: UnorderedMap<String, String>,
+    seen_requests: UnorderedMap<String, Vec<String>>,
+    gift_offers: UnorderedMap<String, GiftOffer>,
+    root_accounts: UnorderedMap<String, String>,
+    pending_transfers: UnorderedMap<String, String>,
+    links_of_user: UnorderedMap<String, UnorderedSet<String>>,
+}
+
+// State layout of the first deployed version, before any field was added.
+#[derive(BorshDeserialize)]
+struct OldContract {
+    folders_v2: UnorderedMap<String, FolderV2>,
+    users: UnorderedMap<String, User>,
+    files: UnorderedMap<String, File>,
+    shared_docs: UnorderedMap<String, ShareDoc>,
+    shared_doc_of_user: UnorderedMap<String, UnorderedSet<String>>,
 }
 
 impl Default for Contract {
@@ -68,450 +659,3954 @@ impl Default for Contract {
             files: UnorderedMap::new(b"f".to_vec()),
             shared_docs: UnorderedMap::new(b"sd".to_vec()),
             shared_doc_of_user: UnorderedMap::new(b"sdou".to_vec()),
+            storage_used: UnorderedMap::new(b"su".to_vec()),
+            quotas: UnorderedMap::new(b"q".to_vec()),
+            usage: UnorderedMap::new(b"us".to_vec()),
+            default_quota: DEFAULT_QUOTA,
+            owner_id: env::current_account_id(),
+            paused: false,
+            trashed: UnorderedMap::new(b"tr".to_vec()),
+            trash_of_user: UnorderedMap::new(b"trou".to_vec()),
+            shared_by_user: UnorderedMap::new(b"sdbu".to_vec()),
+            public_links: UnorderedMap::new(b"pl".to_vec()),
+            favorites: UnorderedMap::new(b"fav".to_vec()),
+            version: CONTRACT_VERSION,
+            stats: UnorderedMap::new(b"st".to_vec()),
+            max_depth: DEFAULT_MAX_DEPTH,
+            share_counts: UnorderedMap::new(b"shc".to_vec()),
+            max_files_per_folder: DEFAULT_MAX_FILES_PER_FOLDER,
+            activity: UnorderedMap::new(b"act".to_vec()),
+            file_parent: UnorderedMap::new(b"fp".to_vec()),
+            seen_requests: UnorderedMap::new(b"sr".to_vec()),
+            gift_offers: UnorderedMap::new(b"go".to_vec()),
+            root_accounts: UnorderedMap::new(b"ra".to_vec()),
+            pending_transfers: UnorderedMap::new(b"pt".to_vec()),
+            links_of_user: UnorderedMap::new(b"lou".to_vec()),
         }
     }
 }
 
 #[near_bindgen]
 impl Contract {
-    pub fn sign_up(&mut self, _public_key: String, _encyted_token: String, _created_at: u64) {
-        env::log(
-            format!(
-                "public_key: {}, encryted_token: {}",
-                &_public_key, &_encyted_token
-            )
-            .as_bytes(),
+    #[init]
+    pub fn new(owner_id: String) -> Self {
+        Self {
+            owner_id,
+            ..Default::default()
+        }
+    }
+
+    // Must be called once right after deploying over the first version's state.
+    // Only that layout, the five maps in `OldContract`, is understood: state
+    // written by any later build already has some of the newer fields and is
+    // rejected as an unknown layout, so upgrading from one isn't supported.
+    // Stored files and folders pick up their new fields when they are read.
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "Only the contract account can migrate"
         );
-        let account_id = env::signer_account_id();
-        let user = User {
-            public_key: _public_key,
-            encrypted_token: _encyted_token,
+        let state = match env::storage_read(b"STATE") {
+            Some(state) => state,
+            None => env::panic(b"Contract state not found"),
         };
-        self.users.insert(&account_id, &user);
+        if let Ok(current) = Contract::try_from_slice(&state) {
+            env::panic(
+                format!("Contract state is already at version {}", current.version).as_bytes(),
+            );
+        }
 
-        let root_shared_folder_v2 = FolderV2 {
-            name: String::from("root"),
-            files: Vec::new(),
-            parent: String::from(&account_id[..]),
-            children: Vec::new(),
-            folder_password: None,
-            created_by: String::from(&account_id[..]),
-            created_at: _created_at,
-            folder_type: None,
+        let old: OldContract = match OldContract::try_from_slice(&state) {
+            Ok(old) => old,
+            Err(_) => env::panic(b"Unknown contract state layout"),
         };
-        self.folders_v2.insert(&account_id, &root_shared_folder_v2);
+        Self {
+            folders_v2: old.folders_v2,
+            users: old.users,
+            files: old.files,
+            shared_docs: old.shared_docs,
+            shared_doc_of_user: old.shared_doc_of_user,
+            ..Default::default()
+        }
     }
 
-    pub fn verify_accessible(
-        &self,
-        root_folder: &Option<FolderV2>,
-        folder_id: String,
-        account_id: String,
-    ) {
-        match root_folder {
-            Some(folder) => {
-                let owner = &folder.parent;
-                let root_folder_id = &folder_id;
-                let share_doc_id = format!("{}_{}_{}", &owner, &account_id, &root_folder_id);
-                if !owner.eq(&account_id) {
-                    match self.shared_docs.get(&share_doc_id) {
-                        Some(share_doc) => {
-                            assert_eq!(
-                                share_doc.permission, 2,
-                                "You don't have permission to change this folder {}",
-                                &share_doc_id
-                            );
-                        }
-                        None => {
-                            assert!(false, "You were not shared this doc {}", &share_doc_id);
-                        }
-                    }
-                }
-            }
-            None => {
-                assert!(false, "You don't have permission to change this folder!");
-            }
-        }
+    pub fn get_version(&self) -> u32 {
+        self.version
     }
 
-    pub fn validate_folder(&self, _folder_id: String) {
-        match self.users.get(&&_folder_id) {
-            Some(_) => {
-                assert!(false, "folder_id can't eq user_id");
-            }
-            None => {}
-        }
+    pub fn version(&self) -> String {
+        VERSION.to_string()
+    }
 
-        match self.folders_v2.get(&_folder_id) {
-            Some(_) => {
-                assert!(false, "Folder id already exists");
-            }
-            None => {}
-        }
+    // Values clients have to send for the numeric enums, and which optional
+    // features this build ships with.
+    pub fn spec(&self) -> Value {
+        json!({
+            "version": VERSION,
+            "state_version": self.version,
+            "doc_types": { "file": u8::from(DocType::File), "folder": u8::from(DocType::Folder) },
+            "folder_types": {
+                "common": u8::from(FolderKind::Common),
+                "shared": u8::from(FolderKind::Shared),
+            },
+            "permissions": {
+                "read": u8::from(Permission::Read),
+                "write": u8::from(Permission::Write),
+                "manage": u8::from(Permission::Manage),
+            },
+            "features": ["trash", "public_links", "tags", "favorites", "versions", "activity"],
+        })
     }
 
-    pub fn validate_user(&self, account_id: String, owner_id: String) {
-        assert_eq!(
-            &account_id, &owner_id,
-            "Owner not match {}, {}",
-            &account_id, &owner_id
-        );
+    pub fn get_owner(&self) -> String {
+        self.owner_id.clone()
     }
 
-    pub fn validate_file(&self, _file_id: String) {
-        match self.files.get(&_file_id) {
-            Some(_) => {
-                assert!(false, "file already exist");
-            }
-            None => {}
-        }
+    pub fn set_owner(&mut self, new_owner: String) {
+        self.assert_owner();
+        self.owner_id = new_owner;
     }
 
-    pub fn validate_folder_type(&self, root_folder: &Option<FolderV2>, _folder_type: u8) {
-        match root_folder {
-            Some(folder_parsed) => {
-                if folder_parsed.folder_type.is_some() {
-                    assert_eq!(
-                        folder_parsed.folder_type.unwrap(),
-                        _folder_type,
-                        "folder type invalid"
-                    );
-                } else {
-                    assert!(false, "root folder is not found!");
-                }
-            }
-            None => {
-                assert!(false, "root folder is not found!");
-            }
-        }
+    pub fn is_paused(&self) -> bool {
+        self.paused
     }
 
-    pub fn create_folder_v2(
-        &mut self,
-        _id: String,
-        _name: String,
-        _parent: String,
-        _password: Option<String>,
-        _type: Option<u8>,
-        _created_at: u64,
-    ) {
-        self.validate_folder(String::from(&_id));
-        let _account_id = env::signer_account_id();
+    pub fn set_paused(&mut self, value: bool) {
+        self.assert_owner();
+        self.paused = value;
+    }
 
-        if _parent.ne(&_account_id) {
-            let (root_folder, folder_id) = self.get_root(String::from(&_parent[..]));
-            self.verify_accessible(&root_folder, folder_id, _account_id.clone());
-        }
+    fn assert_not_paused(&self) {
+        assert!(!self.paused, "Contract is paused");
+    }
 
-        let mut folder_password: Option<String> = None;
-        let mut folder_type: Option<u8> = None;
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "Only the contract owner can call this method"
+        );
+    }
 
-        if (_parent.eq(&_account_id) && _type.is_some()) {
-            if (_type.unwrap()) == 2 {
-                folder_password = _password;
-            }
-            folder_type = _type;
-        }
+    // The root folder is keyed by the account id unless `_root_id` names
+    // another one; see `get_root_folder_id`.
+    #[payable]
+    pub fn sign_up(
+        &mut self,
+        _public_key: String,
+        _encyted_token: String,
+        _created_at: u64,
+        _root_id: Option<String>,
+    ) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        env::log(format!("public_key: {}", &_public_key).as_bytes());
+        let account_id = env::signer_account_id();
+        assert!(
+            self.users.get(&account_id).is_none(),
+            "Account {} is already registered",
+            &account_id
+        );
+        let root_id = _root_id.unwrap_or_else(|| account_id.clone());
+        self.assert_root_id_free(&account_id, &root_id);
+        let user = User {
+            public_key: _public_key,
+            encrypted_token: _encyted_token,
+            root_id: String::new(),
+        };
+        self.users.insert(&account_id, &user);
+        self.set_root_id(&account_id, &root_id);
+        self.insert_root_folder(&account_id, &root_id, _created_at);
+        self.charge_storage(initial_storage);
+    }
 
-        match self.folders_v2.get(&_parent) {
-            Some(mut folder) => {
-                folder.children.push(_id.clone());
-                self.folders_v2.insert(&_parent, &folder);
-
-                let new_folder = FolderV2 {
-                    name: String::from(&_name[..]),
-                    files: Vec::new(),
-                    parent: String::from(&_parent[..]),
-                    children: Vec::new(),
-                    folder_password: folder_password,
-                    folder_type: folder_type,
-                    created_by: _account_id,
-                    created_at: _created_at,
-                };
-                self.folders_v2.insert(&_id, &new_folder);
-            }
-            None => {
-                env::log(format!("Folder not found: '{}'", _parent).as_bytes());
-            }
+    // Replaces a registered signer's keys but keeps an existing root folder, and
+    // with it every folder and file below it. New accounts use `sign_up`.
+    #[payable]
+    pub fn re_register(&mut self, _public_key: String, _encrypted_token: String, _created_at: u64) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let account_id = env::signer_account_id();
+        let mut user = match self.users.get(&account_id) {
+            Some(user) => user,
+            None => env::panic(format!("User not found: '{}'", &account_id).as_bytes()),
+        };
+        user.public_key = _public_key;
+        user.encrypted_token = _encrypted_token;
+        let root_id = self.root_id_of(&account_id);
+        self.users.insert(&account_id, &user);
+        if self.folders_v2.get(&root_id).is_none() {
+            self.insert_root_folder(&account_id, &root_id, _created_at);
         }
+        self.charge_storage(initial_storage);
     }
 
-    pub fn create_file_v2(
-        &mut self,
-        _folder: String,
-        _file_id: String,
-        _cid: String,
-        _name: String,
-        _encryted_password: Option<String>,
-        _file_type: String,
-        _created_at: u64,
-    ) {
-        self.validate_file(_file_id.clone());
+    // Clients should look the root up here rather than assume its id. Roots
+    // are keyed by the account id unless one was picked on `sign_up` or moved
+    // with `migrate_root`.
+    pub fn get_root_folder_id(&self, account_id: String) -> Option<String> {
+        let root_id = self.root_id_of(&account_id);
+        self.folders_v2.get(&root_id).map(|_| root_id)
+    }
+
+    // Re-keys the signer's root folder, e.g. one still keyed by the account id.
+    // Its files, top-level folders and trashed items are repointed in the same
+    // call, so a root with more than MAX_NODES_PER_CALL of those is refused.
+    #[payable]
+    pub fn migrate_root(&mut self, _new_root_id: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
         let _account_id = env::signer_account_id();
-        let (root_folder, folder_id) = self.get_root(_folder.clone());
-        self.verify_accessible(&root_folder, folder_id, _account_id.clone());
-        match self.folders_v2.get(&_folder) {
-            Some(mut folder) => {
-                let index = folder.files.iter().position(|x| *x == _file_id);
-                if index.is_none() {
-                    folder.files.push(_file_id.clone());
+        assert!(
+            self.users.get(&_account_id).is_some(),
+            "User not found: '{}'",
+            &_account_id
+        );
+        let old_root_id = self.root_id_of(&_account_id);
+        self.assert_root_id_free(&_account_id, &_new_root_id);
+        let mut root = match self.folders_v2.remove(&old_root_id) {
+            Some(root) => root,
+            None => env::panic(format!("Folder not found: '{}'", &old_root_id).as_bytes()),
+        };
+        let trashed_ids: Vec<String> = match self.trash_of_user.get(&_account_id) {
+            Some(trash) => trash.iter().collect(),
+            None => vec![],
+        };
+        assert!(
+            root.children.len() + root.files.len() + trashed_ids.len() <= MAX_NODES_PER_CALL,
+            "Root folder {} has too many entries to migrate in one call",
+            &old_root_id
+        );
+
+        for child_id in root.children.iter() {
+            if let Some(mut child) = self.folders_v2.get(child_id) {
+                child.parent = _new_root_id.clone();
+                self.folders_v2.insert(child_id, &child);
+            }
+        }
+        for file_id in root.files.iter() {
+            self.file_parent.insert(file_id, &_new_root_id);
+        }
+        for trashed_id in trashed_ids.iter() {
+            if let Some(mut item) = self.trashed.get(trashed_id) {
+                if item.parent == old_root_id {
+                    item.parent = _new_root_id.clone();
+                    self.trashed.insert(trashed_id, &item);
+                    if let Some(mut folder) = self.folders_v2.get(trashed_id) {
+                        folder.parent = _new_root_id.clone();
+                        self.folders_v2.insert(trashed_id, &folder);
+                    }
                 }
+            }
+        }
+        root.parent = _new_root_id.clone();
+        touch_folder(&mut root);
+        self.folders_v2.insert(&_new_root_id, &root);
+        self.set_root_id(&_account_id, &_new_root_id);
+        self.log_event(
+            "root_migrated",
+            json!({ "folder_id": _new_root_id, "previous_id": old_root_id }),
+        );
+        self.charge_storage(initial_storage);
+    }
 
-                let new_file = File {
-                    cid: _cid,
-                    name: _name,
-                    encrypted_password: _encryted_password,
-                    file_type: _file_type,
-                    created_at: _created_at,
-                    created_by: _account_id.clone(),
-                    last_update: _created_at,
-                    update_by: _account_id,
-                };
+    fn insert_root_folder(&mut self, account_id: &str, root_id: &String, created_at: u64) {
+        let created_at = or_block_time(created_at);
+        let root_shared_folder_v2 = FolderV2 {
+            name: String::from("root"),
+            files: Vec::new(),
+            parent: root_id.clone(),
+            children: Vec::new(),
+            folder_password: None,
+            created_by: account_id.to_string(),
+            created_at,
+            folder_type: None,
+            tags: Vec::new(),
+            last_update: env::block_timestamp(),
+            update_by: account_id.to_string(),
+            owners: vec![account_id.to_string()],
+            block_time: env::block_timestamp(),
+        };
+        self.folders_v2.insert(root_id, &root_shared_folder_v2);
+    }
+
+    // A root id must not clash with a doc or, unless it is the account's own
+    // id, with another account.
+    fn assert_root_id_free(&self, account_id: &String, root_id: &String) {
+        assert!(!root_id.is_empty(), "Root id can't be empty");
+        assert!(
+            root_id == account_id || self.users.get(root_id).is_none(),
+            "folder_id can't eq user_id"
+        );
+        assert!(
+            self.folders_v2.get(root_id).is_none() && self.files.get(root_id).is_none(),
+            "Folder id {} is already taken",
+            root_id
+        );
+    }
+
+    // Records `root_id` as the registered account's root, replacing the one it
+    // had.
+    fn set_root_id(&mut self, account_id: &String, root_id: &String) {
+        let mut user = self.users.get(account_id).unwrap();
+        if !user.root_id.is_empty() {
+            self.root_accounts.remove(&user.root_id);
+        }
+        if root_id == account_id {
+            user.root_id = String::new();
+        } else {
+            self.root_accounts.insert(root_id, account_id);
+            user.root_id = root_id.clone();
+        }
+        self.users.insert(account_id, &user);
+    }
+
+    // Drive roots are keyed by the account id unless the account picked
+    // another one.
+    fn root_id_of(&self, account_id: &str) -> String {
+        match self.users.get(&account_id.to_string()) {
+            Some(user) if !user.root_id.is_empty() => user.root_id,
+            _ => account_id.to_string(),
+        }
+    }
+
+    fn account_of_root(&self, root_id: &str) -> String {
+        self.root_accounts
+            .get(&root_id.to_string())
+            .unwrap_or_else(|| root_id.to_string())
+    }
+
+    // The account the drive a root sits in belongs to.
+    fn root_owner(&self, root_folder: &Option<FolderV2>) -> Option<String> {
+        root_folder
+            .as_ref()
+            .map(|root| self.account_of_root(&root.parent))
+    }
 
-                self.folders_v2.insert(&_folder, &folder);
-                self.files.insert(&_file_id, &new_file);
+    // Co-owners listed on a root have the same rights as the account it sits under.
+    fn is_root_owner(&self, root_folder: &Option<FolderV2>, account_id: &str) -> bool {
+        match root_folder {
+            Some(root) => {
+                self.account_of_root(&root.parent) == account_id
+                    || root.owners.iter().any(|owner| owner == account_id)
             }
-            None => {}
+            None => false,
         }
     }
 
-    pub fn share_file_v2(
+    #[payable]
+    pub fn update_encrypted_token(&mut self, _encrypted_token: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        match self.users.get(&_account_id) {
+            Some(mut user) => {
+                user.encrypted_token = _encrypted_token;
+                self.users.insert(&_account_id, &user);
+                self.log_event("encrypted_token_updated", json!({}));
+            }
+            None => env::panic(format!("User not found: '{}'", &_account_id).as_bytes()),
+        }
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn update_public_key(&mut self, _public_key: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        match self.users.get(&_account_id) {
+            Some(mut user) => {
+                user.public_key = _public_key;
+                self.users.insert(&_account_id, &user);
+                self.log_event("public_key_updated", json!({}));
+            }
+            None => env::panic(format!("User not found: '{}'", &_account_id).as_bytes()),
+        }
+        self.charge_storage(initial_storage);
+    }
+
+    // Hands the signer's drive over to `_new_owner`, one batch of nodes per call.
+    // The signer's outgoing shares move along and are keyed by the new owner.
+    // Pass the returned cursor back until it comes back as None; a cursor is only
+    // accepted while a transfer from the signer to `_new_owner` is in progress.
+    #[payable]
+    pub fn transfer_ownership(
         &mut self,
-        _file_id: String,
-        _share_with: String,
-        _parent_folder: String,
-        _password: String,
-        _permission: u8,
-        _created_at: u64,
-    ) {
+        _new_owner: String,
+        _cursor: Option<Vec<String>>,
+    ) -> Option<Vec<String>> {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
         let _account_id = env::signer_account_id();
         assert_ne!(
-            &_account_id, &_share_with,
-            "can't share to your self {} - {}",
-            &_account_id, &_share_with
+            _account_id, _new_owner,
+            "Cannot transfer a drive to the same account"
         );
-        let (root_folder, folder_id) = self.get_root(_parent_folder.clone());
-        self.verify_accessible(&root_folder, folder_id, _account_id.clone());
-        self.validate_folder_type(&root_folder, 1);
 
-        match self.folders_v2.get(&_parent_folder) {
-            Some(folder) => {
-                let index = folder.files.iter().position(|f| f.eq(&_file_id));
+        let mut queue: VecDeque<String> = match _cursor {
+            Some(cursor) => {
                 assert_eq!(
-                    index.is_none(),
-                    false,
-                    "file {} not found in folder {}",
-                    &_file_id,
-                    &_parent_folder
+                    self.pending_transfers.get(&_account_id).as_ref(),
+                    Some(&_new_owner),
+                    "No transfer to {} in progress",
+                    &_new_owner
                 );
+                cursor.into_iter().collect()
             }
             None => {
-                env::log(format!("Folder not found: '{}'", _parent_folder).as_bytes());
+                assert!(
+                    self.users.get(&_new_owner).is_none(),
+                    "Account {} is already registered",
+                    &_new_owner
+                );
+                let user = match self.users.remove(&_account_id) {
+                    Some(user) => user,
+                    None => env::panic(format!("User not found: '{}'", &_account_id).as_bytes()),
+                };
+                // A root keyed by the account moves to the new owner's key; a
+                // custom root keeps its id and only changes hands.
+                let root_id = if user.root_id.is_empty() {
+                    assert!(
+                        self.folders_v2.get(&_new_owner).is_none(),
+                        "Account {} is already registered",
+                        &_new_owner
+                    );
+                    match self.folders_v2.remove(&_account_id) {
+                        Some(root) => {
+                            self.folders_v2.insert(&_new_owner, &root);
+                        }
+                        None => {
+                            env::panic(format!("Folder not found: '{}'", &_account_id).as_bytes())
+                        }
+                    }
+                    _new_owner.clone()
+                } else {
+                    self.root_accounts.insert(&user.root_id, &_new_owner);
+                    user.root_id.clone()
+                };
+                self.users.insert(&_new_owner, &user);
+                self.pending_transfers.insert(&_account_id, &_new_owner);
+                let mut queue = VecDeque::new();
+                queue.push_back(root_id);
+                queue
             }
-        }
-
-        let share_doc_id = format!("{}_{}_{}", &_account_id, &_share_with, &_file_id);
-        let share_doc = ShareDoc {
-            doc_id: _file_id,
-            share_password: _password,
-            permission: _permission,
-            created_at: _created_at,
-            doc_type: 1,
         };
 
-        self.shared_docs.insert(&share_doc_id, &share_doc);
-        match self.shared_doc_of_user.get(&_share_with) {
-            Some(mut user_shared_with_docs) => {
-                user_shared_with_docs.insert(&share_doc_id);
-                self.shared_doc_of_user
-                    .insert(&_share_with, &user_shared_with_docs);
+        let mut processed = self.rekey_shares(&_account_id, &_new_owner, MAX_NODES_PER_CALL);
+        while let Some(folder_id) = queue.pop_front() {
+            if processed >= MAX_NODES_PER_CALL {
+                queue.push_front(folder_id);
+                break;
             }
-            None => {
-                let mut files_prefix = Vec::with_capacity(33);
-                files_prefix.push(b's');
-                files_prefix.extend(env::sha256(_account_id.as_bytes()));
-                let mut new_shared_set = UnorderedSet::new(files_prefix.to_vec());
-                new_shared_set.insert(&share_doc_id);
-                self.shared_doc_of_user
-                    .insert(&_share_with, &new_shared_set);
+            if let Some(mut folder) = self.folders_v2.get(&folder_id) {
+                processed +=
+                    self.transfer_folder(&folder_id, &mut folder, &_account_id, &_new_owner, false)
+                        + 1;
+                if folder_id == _new_owner {
+                    for file_id in folder.files.iter() {
+                        self.file_parent.insert(file_id, &folder_id);
+                    }
+                }
+                self.folders_v2.insert(&folder_id, &folder);
+                queue.extend(folder.children.iter().cloned());
             }
         }
+
+        let cursor = if queue.is_empty() && self.shared_by_user.get(&_account_id).is_none() {
+            self.pending_transfers.remove(&_account_id);
+            self.log_event("ownership_transferred", json!({ "new_owner": _new_owner }));
+            None
+        } else {
+            Some(queue.into_iter().collect())
+        };
+        self.charge_storage(initial_storage);
+        cursor
     }
 
-    pub fn share_folder_v2(
+    // Offers one of the signer's top-level folders to `_to`. Nothing moves until
+    // `_to` calls `accept_gift`; offering it again replaces the previous offer.
+    #[payable]
+    pub fn gift_folder(&mut self, _folder_id: String, _to: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        assert_ne!(_account_id, _to, "Cannot gift a folder to yourself");
+        assert!(
+            self.users.get(&_to).is_some() && self.folders_v2.get(&self.root_id_of(&_to)).is_some(),
+            "User not found: '{}'",
+            &_to
+        );
+        self.assert_own_top_level(&_folder_id, &_account_id);
+        if let Some(offer) = self.gift_offers.get(&_folder_id) {
+            assert!(
+                !offer.accepted,
+                "Folder {} is still being handed over",
+                &_folder_id
+            );
+        }
+
+        self.gift_offers.insert(
+            &_folder_id,
+            &GiftOffer {
+                from: _account_id,
+                to: _to.clone(),
+                accepted: false,
+            },
+        );
+        self.log_event(
+            "folder_gift_offered",
+            json!({ "folder_id": _folder_id, "to": _to }),
+        );
+        self.charge_storage(initial_storage);
+    }
+
+    // Withdraws or declines an offer that has not been accepted yet. Either side
+    // of the offer may call it.
+    #[payable]
+    pub fn cancel_gift(&mut self, _folder_id: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        let offer = match self.gift_offers.get(&_folder_id) {
+            Some(offer) => offer,
+            None => env::panic(format!("No gift offered for '{}'", &_folder_id).as_bytes()),
+        };
+        assert!(
+            offer.from == _account_id || offer.to == _account_id,
+            "Only the giver or the recipient can cancel a gift"
+        );
+        assert!(
+            !offer.accepted,
+            "Folder {} is still being handed over",
+            &_folder_id
+        );
+        self.gift_offers.remove(&_folder_id);
+        self.log_event("folder_gift_cancelled", json!({ "folder_id": _folder_id }));
+        self.charge_storage(initial_storage);
+    }
+
+    // Moves a folder offered to the signer into their drive. It stays a
+    // top-level folder, so no depth changes and `max_depth` holds as before.
+    // The giver's shares of the subtree are dropped and the files count against
+    // the signer's quota.
+    // Pass the returned cursor back until it comes back as None; if the quota
+    // runs out, free some space and call again with the same cursor.
+    #[payable]
+    pub fn accept_gift(
         &mut self,
         _folder_id: String,
-        _share_with: String,
-        _password: String,
-        _permission: u8,
-        _created_at: u64,
-    ) {
+        _cursor: Option<Vec<String>>,
+    ) -> Option<Vec<String>> {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
         let _account_id = env::signer_account_id();
-        assert_ne!(
-            String::from(&_account_id[..]),
-            String::from(&_share_with[..]),
-            "cannot share to your self"
-        );
-        let (root_folder, root_folder_id) = self.get_root(_folder_id.clone());
+        let mut offer = match self.gift_offers.get(&_folder_id) {
+            Some(offer) => offer,
+            None => env::panic(format!("No gift offered for '{}'", &_folder_id).as_bytes()),
+        };
         assert_eq!(
-            String::from(&root_folder_id[..]),
-            String::from(&_folder_id[..]),
-            "this is not the root folder"
+            offer.to, _account_id,
+            "Folder {} has not been gifted to {}",
+            &_folder_id, &_account_id
         );
-        self.verify_accessible(&root_folder, root_folder_id, _account_id.clone());
-        self.validate_folder_type(&root_folder, 2);
+        let from = offer.from.clone();
 
-        let share_doc_id = format!("{}_{}_{}", &_account_id, &_share_with, &_folder_id);
-        let share_doc = ShareDoc {
-            doc_id: _folder_id,
-            share_password: _password,
-            permission: _permission,
-            created_at: _created_at,
-            doc_type: 2,
+        let mut queue: VecDeque<String> = match _cursor {
+            Some(cursor) => {
+                assert!(
+                    offer.accepted,
+                    "Gift of {} has not been accepted yet",
+                    &_folder_id
+                );
+                cursor.into_iter().collect()
+            }
+            None => {
+                assert!(
+                    !offer.accepted,
+                    "Gift of {} is already accepted, pass the cursor",
+                    &_folder_id
+                );
+                self.assert_own_top_level(&_folder_id, &from);
+                self.remove_shares_where(&from, |contract, share_doc| {
+                    contract.doc_is_within(&share_doc.doc_id, share_doc.doc_type, &_folder_id)
+                });
+                self.remove_child(&self.root_id_of(&from), &_folder_id);
+                let new_root_id = self.root_id_of(&_account_id);
+                let mut new_root = self.folders_v2.get(&new_root_id).unwrap();
+                new_root.children.push(_folder_id.clone());
+                touch_folder(&mut new_root);
+                self.folders_v2.insert(&new_root_id, &new_root);
+
+                offer.accepted = true;
+                self.gift_offers.insert(&_folder_id, &offer);
+                let mut queue = VecDeque::new();
+                queue.push_back(_folder_id.clone());
+                queue
+            }
         };
 
-        self.shared_docs.insert(&share_doc_id, &share_doc);
-        match self.shared_doc_of_user.get(&_share_with) {
-            Some(mut user_shared_with_docs) => {
-                user_shared_with_docs.insert(&share_doc_id);
-                self.shared_doc_of_user
-                    .insert(&_share_with, &user_shared_with_docs);
+        let mut processed: usize = 0;
+        while let Some(folder_id) = queue.pop_front() {
+            if processed >= MAX_NODES_PER_CALL {
+                queue.push_front(folder_id);
+                break;
             }
-            None => {
-                let mut files_prefix = Vec::with_capacity(33);
-                files_prefix.push(b's');
-                files_prefix.extend(env::sha256(_account_id.as_bytes()));
-                let mut new_shared_set = UnorderedSet::new(files_prefix.to_vec());
-                new_shared_set.insert(&share_doc_id);
-                self.shared_doc_of_user
-                    .insert(&_share_with, &new_shared_set);
+            assert_eq!(
+                self.find_root(folder_id.clone()).1,
+                _folder_id,
+                "Folder {} is not part of {}",
+                &folder_id,
+                &_folder_id
+            );
+            if let Some(mut folder) = self.folders_v2.get(&folder_id) {
+                processed +=
+                    self.transfer_folder(&folder_id, &mut folder, &from, &_account_id, true) + 1;
+                self.folders_v2.insert(&folder_id, &folder);
+                queue.extend(folder.children.iter().cloned());
             }
         }
+
+        let cursor = if queue.is_empty() {
+            self.gift_offers.remove(&_folder_id);
+            self.log_event(
+                "folder_gifted",
+                json!({ "folder_id": _folder_id, "from": from }),
+            );
+            None
+        } else {
+            Some(queue.into_iter().collect())
+        };
+        self.charge_storage(initial_storage);
+        cursor
     }
 
-    pub fn remove_file_v2(&mut self, _folder_id: String, _file_id: String) {
+    pub fn get_gift_offer(&self, folder_id: String) -> Option<GiftOffer> {
+        self.gift_offers.get(&folder_id)
+    }
+
+    // Wipes the signer's drive, trash, shares, public links and every other
+    // record kept for the account in batches. The user entry goes last, so
+    // returns false until the caller has called enough times.
+    pub fn delete_account(&mut self) -> bool {
+        self.assert_not_paused();
         let _account_id = env::signer_account_id();
-        let (root_folder, _) = self.get_root(_folder_id.clone());
-        match root_folder {
-            Some(root_folder_unwaped) => {
-                let owner_id = root_folder_unwaped.parent;
-                self.validate_user(_account_id, owner_id);
+        assert!(
+            self.users.get(&_account_id).is_some(),
+            "User not found: '{}'",
+            &_account_id
+        );
+
+        if let Some(trash) = self.trash_of_user.get(&_account_id) {
+            let trashed_ids: Vec<String> = trash.iter().take(MAX_NODES_PER_CALL).collect();
+            for id in trashed_ids.iter() {
+                if let Some(item) = self.trashed.get(id) {
+                    if item.item_type == DocType::File {
+                        self.delete_file(id);
+                    } else if !self.remove_folder_tree(id.clone()) {
+                        return false;
+                    }
+                }
+                self.untrash_item(id, &_account_id);
             }
-            None => {
-                env::log(format!("root folder not found: '{}'", &_folder_id).as_bytes());
+            if trash.len() > trashed_ids.len() as u64 {
+                return false;
             }
         }
-        match self.folders_v2.get(&_folder_id) {
-            Some(mut folder) => {
-                let index = folder
-                    .files
-                    .iter()
-                    .position(|f| *f == _file_id.clone())
-                    .unwrap();
-                folder.files.remove(index);
-                self.folders_v2.insert(&_folder_id, &folder);
-                self.files.remove(&_file_id);
+
+        let root_id = self.root_id_of(&_account_id);
+        if !self.remove_folder_tree(root_id.clone()) {
+            return false;
+        }
+
+        if let Some(shares) = self.shared_by_user.get(&_account_id) {
+            let share_ids: Vec<String> = shares.iter().take(MAX_NODES_PER_CALL).collect();
+            for share_doc_id in share_ids.iter() {
+                match self.shared_docs.get(share_doc_id) {
+                    Some(share) => self.drop_share(share_doc_id, &share, &_account_id),
+                    None => remove_from_set(&mut self.shared_by_user, &_account_id, share_doc_id),
+                }
             }
-            None => {
-                env::log(format!("Folder not found: '{}'", _folder_id).as_bytes());
+            if shares.len() > share_ids.len() as u64 {
+                return false;
             }
         }
-    }
 
-    pub fn remove_folder_v2(&mut self, _folder_id: String) {
-        let _account_id = env::signer_account_id();
-        let (root_folder, _) = self.get_root(_folder_id.clone());
-        match root_folder {
-            Some(root_folder_unwaped) => {
-                let owner_id = root_folder_unwaped.parent;
-                self.validate_user(_account_id, owner_id);
+        if let Some(received) = self.shared_doc_of_user.get(&_account_id) {
+            let share_ids: Vec<String> = received.iter().take(MAX_NODES_PER_CALL).collect();
+            for share_doc_id in share_ids.iter() {
+                if let Some(share) = self.shared_docs.get(share_doc_id) {
+                    match self.share_owner(share_doc_id, &share, &_account_id) {
+                        Some(owner_id) => self.drop_share(share_doc_id, &share, &owner_id),
+                        None => {
+                            self.shared_docs.remove(share_doc_id);
+                        }
+                    }
+                }
+                remove_from_set(&mut self.shared_doc_of_user, &_account_id, share_doc_id);
             }
-            None => {
-                env::log(format!("root folder not found: '{}'", &_folder_id).as_bytes());
+            if received.len() > share_ids.len() as u64 {
+                return false;
             }
         }
 
-        match self.folders_v2.get(&_folder_id) {
-            Some(folder) => {
-                match self.folders_v2.get(&folder.parent) {
-                    Some(mut parent_folder) => {
-                        let index = parent_folder.children.iter().position(|f| *f == _folder_id.clone()).unwrap();
-                        parent_folder.children.remove(index);
-                        self.folders_v2.remove(&_folder_id);
-                        self.folders_v2.insert(&folder.parent, &parent_folder);
-                    },
-                    None => {}
-                }
-            },
-            None => {
-                env::log(format!("Folder not found: '{}'", _folder_id).as_bytes());
+        if let Some(links) = self.links_of_user.get(&_account_id) {
+            let tokens: Vec<String> = links.iter().take(MAX_NODES_PER_CALL).collect();
+            for token in tokens.iter() {
+                self.public_links.remove(token);
+                remove_from_set(&mut self.links_of_user, &_account_id, token);
+            }
+            if links.len() > tokens.len() as u64 {
+                return false;
             }
         }
-    }
 
-    pub fn get_user(&self, account_id: String) -> Option<User> {
-        env::log(format!("Account : '{}'", account_id).as_bytes());
-        match self.users.get(&account_id) {
-            Some(user) => Some(user),
-            None => None
+        if let Some(favorites) = self.favorites.get(&_account_id) {
+            let doc_ids: Vec<String> = favorites.iter().take(MAX_NODES_PER_CALL).collect();
+            for doc_id in doc_ids.iter() {
+                remove_from_set(&mut self.favorites, &_account_id, doc_id);
+            }
+            if favorites.len() > doc_ids.len() as u64 {
+                return false;
+            }
         }
-    }
 
-    pub fn get_shared_doc_of_user(&self, _account_id:String) -> Vec<String> {
-        match self.shared_doc_of_user.get(&_account_id) {
-            Some(shared_docs) => {
-                shared_docs.iter().collect()
-            },
-            None => vec![]
+        self.seen_requests.remove(&_account_id);
+        self.stats.remove(&_account_id);
+        self.usage.remove(&_account_id);
+        self.quotas.remove(&_account_id);
+        self.storage_used.remove(&_account_id);
+        self.root_accounts.remove(&root_id);
+        self.users.remove(&_account_id);
+        if let Some(mut log) = self.activity.remove(&_account_id) {
+            log.entries.clear();
         }
+        // Not recorded as activity, so nothing is left behind for the account.
+        emit_event("account_deleted", json!({ "account_id": _account_id }));
+        true
     }
 
-    pub fn get_shared_doc_detail(&self, _doc_id:String) -> (Option<ShareDoc>, Option<FolderV2>, Option<File>, String) {
-        match self.shared_docs.get(&_doc_id) {
-            Some(doc) => {
-                let file = self.files.get(&doc.doc_id);
-                let folder = self.folders_v2.get(&doc.doc_id);
-                (Some(doc), folder, file, _doc_id)
-            },
-            None => (None,None,None,_doc_id)
-        }
+    // Access is decided on the root of the tree, so a write share on a shared
+    // folder covers creating, renaming, updating and removing anything nested
+    // below it. Removed items go to the trash of the tree's owner.
+    pub fn verify_accessible(
+        &self,
+        root_folder: &Option<FolderV2>,
+        folder_id: String,
+        account_id: String,
+    ) {
+        self.check_access(root_folder, &folder_id, &account_id, true);
     }
 
-    pub fn get_file_info(&self, file_id: String) -> Option<File> {
-        match self.files.get(&file_id) {
-            Some(file) => Some(file),
-            None => None,
-        }
+    pub fn assert_can_read(&self, folder_id: String, account_id: String) {
+        let (root_folder, root_folder_id) = self.find_root(folder_id);
+        self.check_access(&root_folder, &root_folder_id, &account_id, false);
     }
 
-    pub fn get_folder_info_v2(&self, folder_id: String) -> Option<FolderV2> {
-        match self.folders_v2.get(&folder_id) {
-            Some(folder) => Some(folder),
-            None => None,
+    pub fn assert_can_write(&self, folder_id: String, account_id: String) {
+        let (root_folder, root_folder_id) = self.find_root(folder_id);
+        self.check_access(&root_folder, &root_folder_id, &account_id, true);
+    }
+
+    // Same rule as `assert_can_write`, answered with a bool instead of a panic.
+    pub fn can_write(&self, folder_id: String, account_id: String) -> bool {
+        let (root_folder, root_folder_id) = self.find_root(folder_id);
+        matches!(
+            self.root_permission(&root_folder, &root_folder_id, &account_id),
+            Ok(permission) if permission.can_write()
+        )
+    }
+
+    pub fn can_access(&self, doc_id: String, account_id: String) -> Option<u8> {
+        self.doc_permission(&doc_id, &account_id).ok().map(u8::from)
+    }
+
+    // Like `can_access`, with 0 standing for no access, so the frontend can
+    // decide what to show without attempting a write.
+    pub fn effective_permission(&self, doc_id: String, account_id: String) -> u8 {
+        self.doc_permission(&doc_id, &account_id)
+            .map(u8::from)
+            .unwrap_or(0)
+    }
+
+    // Like `can_access`, but tells the client why access was refused.
+    pub fn check_doc_access(
+        &self,
+        doc_id: String,
+        account_id: String,
+        need_write: bool,
+    ) -> Result<Permission, DsError> {
+        let permission = self.doc_permission(&doc_id, &account_id)?;
+        if need_write && !permission.can_write() {
+            return Err(DsError::InsufficientPermission);
         }
+        Ok(permission)
     }
 
-    pub fn get_root(&self, folder_id: String) -> (Option<FolderV2>, String) {
-        let mut result = String::from("");
-        match self.folders_v2.get(&folder_id) {
-            Some(folder_by_id) => {
-                let mut current_id = String::from(&folder_id[..]);
-                let mut parent_id = String::from(&folder_by_id.parent[..]);
-                while current_id.ne(&parent_id[..]) {
-                    match self.folders_v2.get(&parent_id) {
-                        Some(folder) => {
-                            let temp = current_id.clone();
-                            current_id = String::from(&parent_id[..]);
-                            parent_id = folder.parent;
-                            if current_id.eq(&parent_id) {
-                                result = String::from(&temp[..]);
-                            }
-                        }
-                        None => {}
-                    };
+    // A file is decided on the root of the tree it is stored in, like every
+    // folder of that tree, and failing that on a share of the file itself.
+    // Files stored before `file_parent` existed count their creator as owner.
+    fn doc_permission(&self, doc_id: &str, account_id: &str) -> Result<Permission, DsError> {
+        let file = match self.files.get(&doc_id.to_string()) {
+            Some(file) => file,
+            None => return self.folder_permission(doc_id, account_id),
+        };
+        let owner_id = match self.file_parent.get(&doc_id.to_string()) {
+            Some(folder_id) => {
+                let permission = self.folder_permission(&folder_id, account_id);
+                if permission.is_ok() {
+                    return permission;
                 }
+                self.folder_owner(&folder_id).unwrap_or(file.created_by)
+            }
+            None if file.created_by == account_id => return Ok(Permission::Write),
+            None => file.created_by,
+        };
+        let share = self.find_share(&owner_id, account_id, doc_id);
+        share_permission(share.map(|(_, share_doc)| share_doc))
+    }
+
+    // An account's own folder is only open to the account; every other folder
+    // follows the root of its tree.
+    fn folder_permission(&self, folder_id: &str, account_id: &str) -> Result<Permission, DsError> {
+        match self.folders_v2.get(&folder_id.to_string()) {
+            Some(folder) if folder.parent == folder_id => {
+                if folder.created_by == account_id {
+                    Ok(Permission::Write)
+                } else {
+                    Err(DsError::NotShared)
+                }
+            }
+            Some(_) => {
+                let (root_folder, root_folder_id) = self.find_root(folder_id.to_string());
+                self.root_permission(&root_folder, &root_folder_id, account_id)
+            }
+            None => Err(DsError::NotFound),
+        }
+    }
+
+    // The account shares of anything in the folder's tree are keyed by.
+    fn folder_owner(&self, folder_id: &str) -> Option<String> {
+        match self.folders_v2.get(&folder_id.to_string()) {
+            Some(folder) if folder.parent == folder_id => Some(folder.created_by),
+            Some(_) => self.root_owner(&self.find_root(folder_id.to_string()).0),
+            None => None,
+        }
+    }
+
+    // Owners and collaborators with a manage share may hand a tree out further;
+    // plain collaborators may only edit it. Shares are always keyed by the
+    // account the root sits under, which is returned, so the owner can revoke
+    // whatever a manager shared.
+    fn assert_can_share(
+        &self,
+        root_folder: &Option<FolderV2>,
+        root_folder_id: &str,
+        account_id: &str,
+    ) -> String {
+        let owner_id = match self.root_owner(root_folder) {
+            Some(owner_id) => owner_id,
+            None => env::panic(b"root folder is not found!"),
+        };
+        assert!(
+            self.is_root_owner(root_folder, account_id)
+                || matches!(
+                    self.root_permission(root_folder, root_folder_id, account_id),
+                    Ok(permission) if permission.can_manage()
+                ),
+            "Only the owner or a manager can share this doc"
+        );
+        owner_id
+    }
+
+    // Owners may replace or change any share of their tree; managers only the
+    // ones they created themselves.
+    fn assert_may_change_share(
+        &self,
+        root_folder: &Option<FolderV2>,
+        owner_id: &str,
+        share_doc: &ShareDoc,
+        account_id: &str,
+    ) {
+        if self.is_root_owner(root_folder, account_id) {
+            return;
+        }
+        if let Some((_, existing)) =
+            self.find_share(owner_id, &share_doc.shared_with, &share_doc.doc_id)
+        {
+            assert_eq!(
+                existing.shared_by, account_id,
+                "Only an owner can change a share an owner created"
+            );
+        }
+    }
+
+    // Looks up the share of `doc_id` with `shared_with` for an owner or manager
+    // of the doc's tree, keyed by `root_owner` like every share of the tree.
+    // Shares of docs that are gone can still be cleaned up by whoever they
+    // are keyed by. Returns the owner key, the share id and the share.
+    fn find_managed_share(
+        &self,
+        doc_id: &str,
+        shared_with: &str,
+        account_id: &str,
+    ) -> (String, String, ShareDoc) {
+        let (root_folder, root_folder_id) = self.doc_root(doc_id);
+        let owner_id = if root_folder.is_some() {
+            self.assert_can_share(&root_folder, &root_folder_id, account_id)
+        } else {
+            account_id.to_string()
+        };
+        // Shares co-owners made before they were keyed by `root_owner` are
+        // still stored under the co-owner.
+        let found = self
+            .find_share(&owner_id, shared_with, doc_id)
+            .map(|share| (owner_id.clone(), share))
+            .or_else(|| {
+                if self.is_root_owner(&root_folder, account_id) {
+                    self.find_share(account_id, shared_with, doc_id)
+                        .map(|share| (account_id.to_string(), share))
+                } else {
+                    None
+                }
+            });
+        let (key_owner, (share_doc_id, share_doc)) = match found {
+            Some(found) => found,
+            None => env::panic(
+                format!(
+                    "Share not found: '{}'",
+                    share_doc_id(&owner_id, shared_with, doc_id)
+                )
+                .as_bytes(),
+            ),
+        };
+        if !self.is_root_owner(&root_folder, account_id) && root_folder.is_some() {
+            assert_eq!(
+                share_doc.shared_by, account_id,
+                "Only an owner can change a share an owner created"
+            );
+        }
+        (key_owner, share_doc_id, share_doc)
+    }
+
+    // The account a share received by `shared_with` is keyed by: the root
+    // owner of its doc, or for older shares whoever created it.
+    fn share_owner(
+        &self,
+        share_id: &str,
+        share_doc: &ShareDoc,
+        shared_with: &str,
+    ) -> Option<String> {
+        if !is_hashed_share_id(share_id) {
+            return share_id
+                .strip_suffix(&format!("_{}_{}", shared_with, share_doc.doc_id))
+                .map(|owner_id| owner_id.to_string());
+        }
+        let root_owner_id = self.root_owner(&self.doc_root(&share_doc.doc_id).0);
+        root_owner_id
+            .into_iter()
+            .chain(Some(share_doc.shared_by.clone()))
+            .find(|owner_id| share_doc_id(owner_id, shared_with, &share_doc.doc_id) == share_id)
+    }
+
+    // The root of the tree a doc is in; a file is placed through `file_parent`.
+    fn doc_root(&self, doc_id: &str) -> (Option<FolderV2>, String) {
+        match self.file_parent.get(&doc_id.to_string()) {
+            Some(folder_id) => self.find_root(folder_id),
+            None => self.find_root(doc_id.to_string()),
+        }
+    }
+
+    fn assert_can_write_doc(&self, doc_id: &str, account_id: &str) {
+        assert!(
+            matches!(self.doc_permission(doc_id, account_id), Ok(permission) if permission.can_write()),
+            "You don't have write access to {}",
+            doc_id
+        );
+    }
+
+    // Owners of the root can always write; everyone else needs a live share.
+    fn root_permission(
+        &self,
+        root_folder: &Option<FolderV2>,
+        root_folder_id: &str,
+        account_id: &str,
+    ) -> Result<Permission, DsError> {
+        let owner = self.root_owner(root_folder).ok_or(DsError::NotFound)?;
+        if self.is_root_owner(root_folder, account_id) {
+            return Ok(Permission::Write);
+        }
+        let share = self.find_share(&owner, account_id, root_folder_id);
+        share_permission(share.map(|(_, share_doc)| share_doc))
+    }
+
+    // Looks a share up under its hashed id first, then under the legacy one.
+    fn find_share(
+        &self,
+        owner_id: &str,
+        shared_with: &str,
+        doc_id: &str,
+    ) -> Option<(String, ShareDoc)> {
+        [
+            share_doc_id(owner_id, shared_with, doc_id),
+            legacy_share_doc_id(owner_id, shared_with, doc_id),
+        ]
+        .iter()
+        .find_map(|id| {
+            self.shared_docs
+                .get(id)
+                .map(|share_doc| (id.clone(), share_doc))
+        })
+    }
+
+    fn check_access(
+        &self,
+        root_folder: &Option<FolderV2>,
+        root_folder_id: &str,
+        account_id: &str,
+        need_write: bool,
+    ) {
+        let owner = match self.root_owner(root_folder) {
+            Some(owner) => owner,
+            None => env::panic(b"You don't have permission to change this folder!"),
+        };
+        let share_doc_id = share_doc_id(&owner, account_id, root_folder_id);
+        match self.root_permission(root_folder, root_folder_id, account_id) {
+            Ok(permission) => assert!(
+                !need_write || permission.can_write(),
+                "You don't have permission to change this folder {}",
+                &share_doc_id
+            ),
+            Err(_) => {
+                env::panic(format!("You were not shared this doc {}", &share_doc_id).as_bytes())
+            }
+        }
+    }
+
+    pub fn validate_folder(&self, _folder_id: String) {
+        assert!(
+            self.users.get(&_folder_id).is_none(),
+            "folder_id can't eq user_id"
+        );
+        assert!(
+            self.folders_v2.get(&_folder_id).is_none(),
+            "Folder id already exists"
+        );
+        assert!(
+            self.files.get(&_folder_id).is_none(),
+            "folder_id can't eq file_id"
+        );
+    }
+
+    // No mutation checks strict ownership any more: folder changes go through
+    // `verify_accessible` and doc changes through `assert_can_write_doc`, and
+    // both decide on the root of the tree. Kept for clients that still call it.
+    pub fn validate_user(&self, account_id: String, owner_id: String) {
+        assert_eq!(
+            &account_id, &owner_id,
+            "Owner not match {}, {}",
+            &account_id, &owner_id
+        );
+    }
+
+    pub fn validate_file(&self, _file_id: String) {
+        assert!(self.files.get(&_file_id).is_none(), "file already exist");
+        assert!(
+            self.folders_v2.get(&_file_id).is_none(),
+            "file_id can't eq folder_id"
+        );
+    }
+
+    // Opt-in checks: each loads every sibling, so they are O(n) in folder size.
+    fn assert_unique_file_name(&self, folder: &FolderV2, _name: &str) {
+        for file_id in folder.files.iter() {
+            if let Some(file) = self.files.get(file_id) {
+                assert_ne!(file.name, _name, "A file named {} already exists", _name);
+            }
+        }
+    }
+
+    fn assert_folder_has_room(&self, folder: &FolderV2, folder_id: &str) {
+        assert!(
+            folder.files.len() < self.max_files_per_folder as usize,
+            "Folder {} already holds {} files; create a subfolder for more",
+            folder_id,
+            self.max_files_per_folder
+        );
+    }
+
+    fn assert_unique_folder_name(&self, folder: &FolderV2, _name: &str) {
+        for child_id in folder.children.iter() {
+            if let Some(child) = self.folders_v2.get(child_id) {
+                assert_ne!(child.name, _name, "A folder named {} already exists", _name);
+            }
+        }
+    }
+
+    fn validate_name(&self, _name: &str) {
+        assert!(!_name.is_empty(), "name can't be empty");
+        assert!(
+            _name.len() <= MAX_NAME_LENGTH,
+            "name can't be longer than {} bytes",
+            MAX_NAME_LENGTH
+        );
+    }
+
+    fn validate_cid(&self, _cid: &str) {
+        assert!(!_cid.is_empty(), "cid can't be empty");
+        assert!(
+            _cid.len() <= MAX_CID_LENGTH,
+            "cid can't be longer than {} bytes",
+            MAX_CID_LENGTH
+        );
+    }
+
+    fn validate_file_media(&self, _mime: &Option<String>, _thumbnail_cid: &Option<String>) {
+        if let Some(mime) = _mime {
+            assert!(
+                mime.len() <= MAX_NAME_LENGTH,
+                "mime can't be longer than {} bytes",
+                MAX_NAME_LENGTH
+            );
+        }
+        if let Some(thumbnail_cid) = _thumbnail_cid {
+            self.validate_cid(thumbnail_cid);
+        }
+    }
+
+    fn validate_file_fields(&self, _cid: &str, _name: &str, _file_type: &str) {
+        self.validate_cid(_cid);
+        self.validate_name(_name);
+        assert!(!_file_type.is_empty(), "file_type can't be empty");
+        assert!(
+            _file_type.len() <= MAX_NAME_LENGTH,
+            "file_type can't be longer than {} bytes",
+            MAX_NAME_LENGTH
+        );
+    }
+
+    pub fn validate_folder_type(&self, root_folder: &Option<FolderV2>, _folder_type: FolderKind) {
+        match root_folder.as_ref().and_then(|folder| folder.folder_type) {
+            Some(folder_type) => assert_eq!(folder_type, _folder_type, "folder type invalid"),
+            None => env::panic(b"root folder is not found!"),
+        }
+    }
+
+    #[payable]
+    pub fn create_folder_v2(
+        &mut self,
+        _id: String,
+        _name: String,
+        _parent: String,
+        _password: Option<String>,
+        _type: Option<FolderKind>,
+        _created_at: u64,
+        _strict: Option<bool>,
+        _request_id: Option<String>,
+    ) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        if !self.is_replayed(&_request_id) {
+            self.insert_folder_v2(_id, _name, _parent, _password, _type, _created_at, _strict);
+        }
+        self.charge_storage(initial_storage);
+    }
+
+    fn insert_folder_v2(
+        &mut self,
+        _id: String,
+        _name: String,
+        _parent: String,
+        _password: Option<String>,
+        _type: Option<FolderKind>,
+        _created_at: u64,
+        _strict: Option<bool>,
+    ) {
+        self.validate_folder(String::from(&_id));
+        self.validate_name(&_name);
+        let _account_id = env::signer_account_id();
+        let _created_at = or_block_time(_created_at);
+        // Checked before any access check, so a typo in the parent id isn't
+        // reported as a permission problem.
+        let mut parent_folder = match self.folders_v2.get(&_parent) {
+            Some(folder) => folder,
+            None => env::panic(format!("Parent folder not found: '{}'", _parent).as_bytes()),
+        };
+        if _strict.unwrap_or(false) {
+            self.assert_unique_folder_name(&parent_folder, &_name);
+        }
+        // The path of the parent starts at the account root, which sits at depth 0.
+        assert!(
+            self.get_path(_parent.clone()).len() <= self.max_depth as usize,
+            "Folders can't be nested more than {} levels deep",
+            self.max_depth
+        );
+        match _type {
+            Some(FolderKind::Shared) => assert!(
+                !_password.as_deref().unwrap_or("").is_empty(),
+                "Shared folders require a password"
+            ),
+            Some(FolderKind::Common) => {
+                assert!(_password.is_none(), "Common folders can't have a password")
+            }
+            None => {}
+        }
+
+        if _parent.ne(&self.root_id_of(&_account_id)) {
+            self.assert_can_write(_parent.clone(), _account_id.clone());
+        }
+
+        let folder_type = _type;
+        let folder_password = if folder_type == Some(FolderKind::Shared) {
+            _password
+        } else {
+            None
+        };
+
+        let owners = if parent_folder.parent == _parent {
+            vec![self.account_of_root(&_parent)]
+        } else {
+            Vec::new()
+        };
+        parent_folder.children.push(_id.clone());
+        touch_folder(&mut parent_folder);
+        self.folders_v2.insert(&_parent, &parent_folder);
+
+        let new_folder = FolderV2 {
+            name: String::from(&_name[..]),
+            files: Vec::new(),
+            parent: String::from(&_parent[..]),
+            children: Vec::new(),
+            folder_password,
+            folder_type,
+            created_by: _account_id.clone(),
+            created_at: _created_at,
+            tags: Vec::new(),
+            last_update: env::block_timestamp(),
+            update_by: _account_id,
+            owners,
+            block_time: env::block_timestamp(),
+        };
+        self.folders_v2.insert(&_id, &new_folder);
+        self.update_stats(&new_folder.created_by, 0, 1);
+        self.log_event(
+            "folder_created",
+            json!({ "folder_id": _id, "parent": _parent }),
+        );
+    }
+
+    #[payable]
+    pub fn create_file_v2(
+        &mut self,
+        _folder: String,
+        _file_id: String,
+        _cid: String,
+        _name: String,
+        _encryted_password: Option<String>,
+        _file_type: String,
+        _size: u64,
+        _created_at: u64,
+        _strict: Option<bool>,
+        _request_id: Option<String>,
+        _mime: Option<String>,
+        _thumbnail_cid: Option<String>,
+    ) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        if !self.is_replayed(&_request_id) {
+            self.insert_file_v2(
+                _folder,
+                _file_id,
+                _cid,
+                _name,
+                _encryted_password,
+                _file_type,
+                _size,
+                _created_at,
+                _strict,
+                _mime,
+                _thumbnail_cid,
+            );
+        }
+        self.charge_storage(initial_storage);
+    }
+
+    // Creates the file, or replaces the content of one the signer created while
+    // keeping its id, creation info and shares. Returns true when it was created.
+    // `_updated_at` only serves as the creation time of a new file.
+    #[payable]
+    pub fn upsert_file_v2(
+        &mut self,
+        _folder: String,
+        _file_id: String,
+        _cid: String,
+        _name: String,
+        _encryted_password: Option<String>,
+        _file_type: String,
+        _size: u64,
+        _updated_at: u64,
+    ) -> bool {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        if self.files.get(&_file_id).is_none() {
+            self.insert_file_v2(
+                _folder,
+                _file_id,
+                _cid,
+                _name,
+                _encryted_password,
+                _file_type,
+                _size,
+                _updated_at,
+                None,
+                None,
+                None,
+            );
+            self.charge_storage(initial_storage);
+            return true;
+        }
+
+        let _account_id = env::signer_account_id();
+        self.validate_file_fields(&_cid, &_name, &_file_type);
+        let mut file = self.get_writable_file(&_folder, &_file_id, &_account_id);
+        assert_eq!(
+            file.created_by, _account_id,
+            "Only the creator of {} can overwrite it",
+            &_file_id
+        );
+        let used = self.usage.get(&_account_id).unwrap_or(0);
+        self.usage
+            .insert(&_account_id, &used.saturating_sub(file.size));
+        self.consume_quota(&_account_id, _size);
+
+        if file.cid != _cid {
+            file.versions.push(FileVersion {
+                cid: file.cid,
+                updated_at: file.last_update,
+                updated_by: file.update_by,
+            });
+            if file.versions.len() > MAX_FILE_VERSIONS {
+                file.versions.remove(0);
+            }
+        }
+        file.cid = _cid;
+        file.name = _name;
+        file.encrypted_password = _encryted_password;
+        file.file_type = _file_type;
+        file.size = _size;
+        file.last_update = env::block_timestamp();
+        file.update_by = _account_id;
+        self.files.insert(&_file_id, &file);
+        self.charge_storage(initial_storage);
+        false
+    }
+
+    fn insert_file_v2(
+        &mut self,
+        _folder: String,
+        _file_id: String,
+        _cid: String,
+        _name: String,
+        _encryted_password: Option<String>,
+        _file_type: String,
+        _size: u64,
+        _created_at: u64,
+        _strict: Option<bool>,
+        _mime: Option<String>,
+        _thumbnail_cid: Option<String>,
+    ) {
+        let _account_id = env::signer_account_id();
+        let _created_at = or_block_time(_created_at);
+        self.assert_can_write(_folder.clone(), _account_id.clone());
+        self.validate_file(_file_id.clone());
+        self.validate_file_fields(&_cid, &_name, &_file_type);
+        self.validate_file_media(&_mime, &_thumbnail_cid);
+        let mut folder = match self.folders_v2.get(&_folder) {
+            Some(folder) => folder,
+            None => env::panic(format!("Folder not found: '{}'", _folder).as_bytes()),
+        };
+        if _strict.unwrap_or(false) {
+            self.assert_unique_file_name(&folder, &_name);
+        }
+        let index = folder.files.iter().position(|x| *x == _file_id);
+        if index.is_none() {
+            self.assert_folder_has_room(&folder, &_folder);
+            folder.files.push(_file_id.clone());
+            self.file_parent.insert(&_file_id, &_folder);
+        }
+
+        self.consume_quota(&_account_id, _size);
+
+        let new_file = File {
+            cid: _cid,
+            name: _name,
+            encrypted_password: _encryted_password,
+            file_type: _file_type,
+            created_at: _created_at,
+            created_by: _account_id.clone(),
+            last_update: env::block_timestamp(),
+            update_by: _account_id,
+            size: _size,
+            versions: Vec::new(),
+            tags: Vec::new(),
+            last_accessed: None,
+            block_time: env::block_timestamp(),
+            description: None,
+            mime: _mime,
+            thumbnail_cid: _thumbnail_cid,
+        };
+
+        touch_folder(&mut folder);
+        self.folders_v2.insert(&_folder, &folder);
+        self.files.insert(&_file_id, &new_file);
+        self.update_stats(&new_file.created_by, 1, 0);
+        self.log_event(
+            "file_created",
+            json!({ "file_id": _file_id, "folder_id": _folder }),
+        );
+    }
+
+    // Creates a folder and its first file together; any failed check aborts
+    // the whole call, so neither is left behind on its own.
+    #[payable]
+    pub fn create_folder_with_file(
+        &mut self,
+        folder: FolderInput,
+        file: FileInput,
+        created_at: u64,
+    ) -> (String, String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        self.insert_folder_v2(
+            folder.id.clone(),
+            folder.name,
+            folder.parent,
+            folder.password,
+            folder.folder_type,
+            created_at,
+            None,
+        );
+        self.insert_file_v2(
+            folder.id.clone(),
+            file.file_id.clone(),
+            file.cid,
+            file.name,
+            file.encrypted_password,
+            file.file_type,
+            file.size,
+            created_at,
+            None,
+            file.mime,
+            file.thumbnail_cid,
+        );
+        self.charge_storage(initial_storage);
+        (folder.id, file.file_id)
+    }
+
+    // Creates every file whose id is still free; the flag is false for ids that
+    // were skipped because they already exist, and for every id of a replayed
+    // request.
+    #[payable]
+    pub fn create_files_v2(
+        &mut self,
+        _folder: String,
+        _files: Vec<FileInput>,
+        _created_at: u64,
+        _request_id: Option<String>,
+    ) -> Vec<(String, bool)> {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        if self.is_replayed(&_request_id) {
+            self.charge_storage(initial_storage);
+            return _files
+                .into_iter()
+                .map(|input| (input.file_id, false))
+                .collect();
+        }
+        assert!(
+            _files.len() <= MAX_NODES_PER_CALL,
+            "Can't create more than {} files in one call",
+            MAX_NODES_PER_CALL
+        );
+        let _account_id = env::signer_account_id();
+        let _created_at = or_block_time(_created_at);
+        self.assert_can_write(_folder.clone(), _account_id.clone());
+        let mut folder = match self.folders_v2.get(&_folder) {
+            Some(folder) => folder,
+            None => env::panic(format!("Folder not found: '{}'", _folder).as_bytes()),
+        };
+
+        let mut results = Vec::new();
+        for input in _files {
+            self.validate_file_fields(&input.cid, &input.name, &input.file_type);
+            self.validate_file_media(&input.mime, &input.thumbnail_cid);
+            if self.files.get(&input.file_id).is_some()
+                || self.folders_v2.get(&input.file_id).is_some()
+                || folder.files.contains(&input.file_id)
+            {
+                results.push((input.file_id, false));
+                continue;
+            }
+            self.consume_quota(&_account_id, input.size);
+
+            let new_file = File {
+                cid: input.cid,
+                name: input.name,
+                encrypted_password: input.encrypted_password,
+                file_type: input.file_type,
+                created_at: _created_at,
+                created_by: _account_id.clone(),
+                last_update: env::block_timestamp(),
+                update_by: _account_id.clone(),
+                size: input.size,
+                versions: Vec::new(),
+                tags: Vec::new(),
+                last_accessed: None,
+                block_time: env::block_timestamp(),
+                description: None,
+                mime: input.mime,
+                thumbnail_cid: input.thumbnail_cid,
+            };
+            self.assert_folder_has_room(&folder, &_folder);
+            folder.files.push(input.file_id.clone());
+            self.file_parent.insert(&input.file_id, &_folder);
+            self.files.insert(&input.file_id, &new_file);
+            self.update_stats(&_account_id, 1, 0);
+            self.log_event(
+                "file_created",
+                json!({ "file_id": input.file_id, "folder_id": _folder }),
+            );
+            results.push((input.file_id, true));
+        }
+
+        touch_folder(&mut folder);
+        self.folders_v2.insert(&_folder, &folder);
+        self.charge_storage(initial_storage);
+        results
+    }
+
+    #[payable]
+    pub fn copy_file(
+        &mut self,
+        _file_id: String,
+        _dest_folder: String,
+        _new_file_id: String,
+        _created_at: u64,
+        _request_id: Option<String>,
+    ) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        if self.is_replayed(&_request_id) {
+            self.charge_storage(initial_storage);
+            return;
+        }
+        let _account_id = env::signer_account_id();
+        let _created_at = or_block_time(_created_at);
+        let (root_folder, folder_id) = self.find_root(_dest_folder.clone());
+        self.verify_accessible(&root_folder, folder_id, _account_id.clone());
+        self.validate_file(_new_file_id.clone());
+
+        let source = match self.files.get(&_file_id) {
+            Some(file) => file,
+            None => env::panic(format!("File not found: '{}'", _file_id).as_bytes()),
+        };
+        match self.folders_v2.get(&_dest_folder) {
+            Some(mut folder) => {
+                self.assert_folder_has_room(&folder, &_dest_folder);
+                folder.files.push(_new_file_id.clone());
+                self.file_parent.insert(&_new_file_id, &_dest_folder);
+                self.consume_quota(&_account_id, source.size);
+
+                let new_file = File {
+                    cid: source.cid,
+                    name: source.name,
+                    encrypted_password: source.encrypted_password,
+                    file_type: source.file_type,
+                    created_at: _created_at,
+                    created_by: _account_id.clone(),
+                    last_update: env::block_timestamp(),
+                    update_by: _account_id,
+                    size: source.size,
+                    versions: Vec::new(),
+                    tags: source.tags,
+                    last_accessed: None,
+                    block_time: env::block_timestamp(),
+                    description: source.description,
+                    mime: source.mime,
+                    thumbnail_cid: source.thumbnail_cid,
+                };
+
+                touch_folder(&mut folder);
+                self.folders_v2.insert(&_dest_folder, &folder);
+                self.files.insert(&_new_file_id, &new_file);
+                self.update_stats(&new_file.created_by, 1, 0);
+                self.log_event(
+                    "file_created",
+                    json!({ "file_id": _new_file_id, "folder_id": _dest_folder }),
+                );
+            }
+            None => env::panic(format!("Folder not found: '{}'", _dest_folder).as_bytes()),
+        }
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn share_file_v2(
+        &mut self,
+        _file_id: String,
+        _share_with: String,
+        _parent_folder: String,
+        _password: String,
+        _permission: Permission,
+        _created_at: u64,
+        _expires_at: Option<u64>,
+        _request_id: Option<String>,
+    ) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        if self.is_replayed(&_request_id) {
+            self.charge_storage(initial_storage);
+            return;
+        }
+        let _created_at = or_block_time(_created_at);
+        let _account_id = env::signer_account_id();
+        assert_ne!(
+            &_account_id, &_share_with,
+            "can't share to your self {} - {}",
+            &_account_id, &_share_with
+        );
+        let (root_folder, folder_id) = self.find_root(_parent_folder.clone());
+        self.verify_accessible(&root_folder, folder_id.clone(), _account_id.clone());
+        let owner_id = self.assert_can_share(&root_folder, &folder_id, &_account_id);
+        assert_ne!(
+            &owner_id, &_share_with,
+            "can't share with the owner {}",
+            &owner_id
+        );
+        self.validate_folder_type(&root_folder, FolderKind::Common);
+
+        // The file may sit in any folder below `_parent_folder`: walking up from
+        // its folder, at most MAX_ANCESTOR_HOPS steps, has to pass it. Files
+        // stored before `file_parent` existed must sit in `_parent_folder`.
+        let is_within = match self.file_parent.get(&_file_id) {
+            Some(folder_id) => self
+                .get_path(folder_id)
+                .iter()
+                .any(|(ancestor_id, _)| *ancestor_id == _parent_folder),
+            None => self
+                .folders_v2
+                .get(&_parent_folder)
+                .is_some_and(|folder| folder.files.contains(&_file_id)),
+        };
+        assert!(
+            is_within,
+            "file {} not found in folder {}",
+            &_file_id, &_parent_folder
+        );
+
+        let share_doc_id = share_doc_id(&owner_id, &_share_with, &_file_id);
+        let share_doc = ShareDoc {
+            doc_id: _file_id,
+            share_password: hash_share_password(&_password),
+            permission: _permission,
+            created_at: _created_at,
+            doc_type: DocType::File,
+            expires_at: _expires_at,
+            shared_with: _share_with.clone(),
+            shared_by: _account_id.clone(),
+        };
+
+        self.assert_may_change_share(&root_folder, &owner_id, &share_doc, &_account_id);
+        self.insert_share(&share_doc_id, &share_doc, &owner_id, &_share_with);
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn share_folder_v2(
+        &mut self,
+        _folder_id: String,
+        _share_with: String,
+        _password: String,
+        _permission: Permission,
+        _created_at: u64,
+        _expires_at: Option<u64>,
+        _request_id: Option<String>,
+    ) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        if self.is_replayed(&_request_id) {
+            self.charge_storage(initial_storage);
+            return;
+        }
+        let _created_at = or_block_time(_created_at);
+        let _account_id = env::signer_account_id();
+        assert_ne!(
+            String::from(&_account_id[..]),
+            String::from(&_share_with[..]),
+            "cannot share to your self"
+        );
+        let (root_folder, root_folder_id) = self.find_root(_folder_id.clone());
+        assert_eq!(
+            String::from(&root_folder_id[..]),
+            String::from(&_folder_id[..]),
+            "this is not the root folder"
+        );
+        self.verify_accessible(&root_folder, root_folder_id.clone(), _account_id.clone());
+        let owner_id = self.assert_can_share(&root_folder, &root_folder_id, &_account_id);
+        assert_ne!(
+            &owner_id, &_share_with,
+            "can't share with the owner {}",
+            &owner_id
+        );
+        self.validate_folder_type(&root_folder, FolderKind::Shared);
+
+        let share_doc_id = share_doc_id(&owner_id, &_share_with, &_folder_id);
+        let share_doc = ShareDoc {
+            doc_id: _folder_id,
+            share_password: hash_share_password(&_password),
+            permission: _permission,
+            created_at: _created_at,
+            doc_type: DocType::Folder,
+            expires_at: _expires_at,
+            shared_with: _share_with.clone(),
+            shared_by: _account_id.clone(),
+        };
+
+        self.assert_may_change_share(&root_folder, &owner_id, &share_doc, &_account_id);
+        self.insert_share(&share_doc_id, &share_doc, &owner_id, &_share_with);
+        self.charge_storage(initial_storage);
+    }
+
+    // Shares a root folder with each target, skipping the signer. Returns the
+    // accounts it was shared with; none for a replayed request.
+    #[payable]
+    pub fn share_folder_batch(
+        &mut self,
+        _folder_id: String,
+        _recipients: Vec<ShareTarget>,
+        _created_at: u64,
+        _request_id: Option<String>,
+    ) -> Vec<String> {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        if self.is_replayed(&_request_id) {
+            self.charge_storage(initial_storage);
+            return vec![];
+        }
+        let _created_at = or_block_time(_created_at);
+        assert!(
+            _recipients.len() <= MAX_NODES_PER_CALL,
+            "Can't share with more than {} accounts in one call",
+            MAX_NODES_PER_CALL
+        );
+        let _account_id = env::signer_account_id();
+        let (root_folder, root_folder_id) = self.find_root(_folder_id.clone());
+        assert_eq!(
+            String::from(&root_folder_id[..]),
+            String::from(&_folder_id[..]),
+            "this is not the root folder"
+        );
+        self.verify_accessible(&root_folder, root_folder_id.clone(), _account_id.clone());
+        let owner_id = self.assert_can_share(&root_folder, &root_folder_id, &_account_id);
+        self.validate_folder_type(&root_folder, FolderKind::Shared);
+
+        let mut shared_with = Vec::new();
+        for target in _recipients {
+            if target.account == _account_id || target.account == owner_id {
+                continue;
+            }
+            let share_doc_id = share_doc_id(&owner_id, &target.account, &_folder_id);
+            let share_doc = ShareDoc {
+                doc_id: _folder_id.clone(),
+                share_password: hash_share_password(&target.password),
+                permission: target.permission,
+                created_at: _created_at,
+                doc_type: DocType::Folder,
+                expires_at: None,
+                shared_with: target.account.clone(),
+                shared_by: _account_id.clone(),
+            };
+            self.assert_may_change_share(&root_folder, &owner_id, &share_doc, &_account_id);
+            self.insert_share(&share_doc_id, &share_doc, &owner_id, &target.account);
+            shared_with.push(target.account);
+        }
+        self.charge_storage(initial_storage);
+        shared_with
+    }
+
+    fn insert_share(
+        &mut self,
+        share_doc_id: &String,
+        share_doc: &ShareDoc,
+        owner_id: &String,
+        share_with: &String,
+    ) {
+        let legacy_id = legacy_share_doc_id(owner_id, share_with, &share_doc.doc_id);
+        let had_legacy = self.shared_docs.remove(&legacy_id).is_some();
+        if had_legacy {
+            remove_from_set(&mut self.shared_doc_of_user, share_with, &legacy_id);
+            remove_from_set(&mut self.shared_by_user, owner_id, &legacy_id);
+        }
+        // A share moved over from its legacy id wasn't counted before either.
+        if self.shared_docs.insert(share_doc_id, share_doc).is_none() {
+            self.update_share_count(&share_doc.doc_id, 1);
+        }
+        insert_into_set(
+            &mut self.shared_doc_of_user,
+            b"sdou",
+            share_with,
+            share_doc_id,
+        );
+        insert_into_set(&mut self.shared_by_user, b"sdbu", owner_id, share_doc_id);
+        self.log_event(
+            "doc_shared",
+            json!({
+                "share_doc_id": share_doc_id,
+                "doc_id": share_doc.doc_id,
+                "shared_with": share_with,
+            }),
+        );
+    }
+
+    #[payable]
+    pub fn revoke_share(&mut self, _doc_id: String, _shared_with: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        let (owner_id, share_doc_id, share_doc) =
+            self.find_managed_share(&_doc_id, &_shared_with, &_account_id);
+        self.drop_share(&share_doc_id, &share_doc, &owner_id);
+        self.log_event(
+            "share_revoked",
+            json!({ "share_doc_id": share_doc_id, "doc_id": _doc_id, "shared_with": _shared_with }),
+        );
+        self.charge_storage(initial_storage);
+    }
+
+    // The recipient-side counterpart of `revoke_share`.
+    #[payable]
+    pub fn leave_share(&mut self, _doc_id: String, _owner: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        let (share_doc_id, share_doc) = match self.find_share(&_owner, &_account_id, &_doc_id) {
+            Some(share) => share,
+            None => env::panic(
+                format!(
+                    "Share not found: '{}'",
+                    share_doc_id(&_owner, &_account_id, &_doc_id)
+                )
+                .as_bytes(),
+            ),
+        };
+        assert_eq!(
+            share_recipient(&share_doc_id, &share_doc, &_owner).as_deref(),
+            Some(&_account_id[..]),
+            "Only the recipient can leave share {}",
+            &share_doc_id
+        );
+        self.drop_share(&share_doc_id, &share_doc, &_owner);
+        self.log_event(
+            "share_left",
+            json!({ "share_doc_id": share_doc_id, "doc_id": _doc_id, "owner": _owner }),
+        );
+        self.charge_storage(initial_storage);
+    }
+
+    // Returns the new link's token. The contract keeps no readable copy of it,
+    // so a replayed request returns an empty string.
+    #[payable]
+    pub fn create_public_link(
+        &mut self,
+        _doc_id: String,
+        _password: String,
+        _permission: Permission,
+        _expires_at: Option<u64>,
+        _request_id: Option<String>,
+    ) -> String {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        if self.is_replayed(&_request_id) {
+            self.charge_storage(initial_storage);
+            return String::new();
+        }
+        let _account_id = env::signer_account_id();
+        let doc_type = if self.files.get(&_doc_id).is_some() {
+            DocType::File
+        } else if self.folders_v2.get(&_doc_id).is_some() {
+            DocType::Folder
+        } else {
+            env::panic(format!("Doc not found: '{}'", &_doc_id).as_bytes())
+        };
+        self.assert_can_write_doc(&_doc_id, &_account_id);
+
+        let created_at = env::block_timestamp();
+        // The block's random seed keeps the token from being derived from the
+        // public inputs; the token itself is never emitted or logged.
+        let mut seed = format!("{}_{}_{}", &_account_id, &_doc_id, created_at).into_bytes();
+        seed.extend(env::random_seed());
+        let token = to_hex(&env::sha256(&seed));
+        assert!(
+            self.public_links.get(&token).is_none(),
+            "Public link already exists, try again"
+        );
+
+        let link = PublicLink {
+            owner: _account_id.clone(),
+            share: ShareDoc {
+                doc_id: _doc_id,
+                share_password: hash_share_password(&_password),
+                permission: _permission,
+                created_at,
+                doc_type,
+                expires_at: _expires_at,
+                shared_with: String::new(),
+                shared_by: _account_id,
+            },
+        };
+        self.public_links.insert(&token, &link);
+        insert_into_set(&mut self.links_of_user, b"lou", &link.owner, &token);
+        self.log_event(
+            "public_link_created",
+            json!({ "doc_id": link.share.doc_id }),
+        );
+        self.charge_storage(initial_storage);
+        token
+    }
+
+    pub fn resolve_public_link(
+        &self,
+        token: String,
+    ) -> Option<(ShareDoc, Option<File>, Option<FolderV2>)> {
+        let link = self.public_links.get(&token)?;
+        if link.share.is_expired() {
+            return None;
+        }
+        let file = self.files.get(&link.share.doc_id);
+        let folder = self.folders_v2.get(&link.share.doc_id).map(redact_folder);
+        Some((redact_share(link.share), file, folder))
+    }
+
+    // The link's password is checked here, as for `open_shared_doc`, since
+    // `resolve_public_link` doesn't return it.
+    pub fn verify_public_link_password(&self, token: String, candidate: String) -> bool {
+        match self.public_links.get(&token) {
+            Some(link) => share_password_matches(&link.share.share_password, &candidate),
+            None => false,
+        }
+    }
+
+    #[payable]
+    pub fn revoke_public_link(&mut self, token: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        match self.public_links.get(&token) {
+            Some(link) => {
+                assert_eq!(
+                    link.owner, _account_id,
+                    "Only the owner can revoke this public link"
+                );
+                self.public_links.remove(&token);
+                remove_from_set(&mut self.links_of_user, &link.owner, &token);
+                self.log_event(
+                    "public_link_revoked",
+                    json!({ "doc_id": link.share.doc_id }),
+                );
+            }
+            None => env::panic(format!("Public link not found: '{}'", &token).as_bytes()),
+        }
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn update_permission(
+        &mut self,
+        _doc_id: String,
+        _shared_with: String,
+        _permission: Permission,
+    ) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        let (_, share_doc_id, mut share_doc) =
+            self.find_managed_share(&_doc_id, &_shared_with, &_account_id);
+        share_doc.permission = _permission;
+        self.shared_docs.insert(&share_doc_id, &share_doc);
+        self.log_event(
+            "share_permission_updated",
+            json!({ "share_doc_id": share_doc_id, "doc_id": _doc_id, "shared_with": _shared_with }),
+        );
+        self.charge_storage(initial_storage);
+    }
+
+    // None makes the share permanent again.
+    #[payable]
+    pub fn extend_share(
+        &mut self,
+        _doc_id: String,
+        _shared_with: String,
+        _new_expires_at: Option<u64>,
+    ) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        let (_, share_doc_id, mut share_doc) =
+            self.find_managed_share(&_doc_id, &_shared_with, &_account_id);
+        share_doc.expires_at = _new_expires_at;
+        self.shared_docs.insert(&share_doc_id, &share_doc);
+        self.log_event(
+            "share_extended",
+            json!({ "share_doc_id": share_doc_id, "doc_id": _doc_id, "shared_with": _shared_with }),
+        );
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn rename_file(&mut self, _folder_id: String, _file_id: String, _new_name: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        self.validate_name(&_new_name);
+        let _account_id = env::signer_account_id();
+        let mut file = self.get_writable_file(&_folder_id, &_file_id, &_account_id);
+        file.name = _new_name;
+        file.last_update = env::block_timestamp();
+        file.update_by = _account_id;
+        self.files.insert(&_file_id, &file);
+        self.log_event(
+            "file_renamed",
+            json!({ "file_id": _file_id, "folder_id": _folder_id }),
+        );
+        self.charge_storage(initial_storage);
+    }
+
+    // None marks the file as unencrypted.
+    #[payable]
+    pub fn set_file_password(&mut self, _file_id: String, _encrypted_password: Option<String>) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        self.assert_can_write_doc(&_file_id, &_account_id);
+        let mut file = match self.files.get(&_file_id) {
+            Some(file) => file,
+            None => env::panic(format!("File not found: '{}'", &_file_id).as_bytes()),
+        };
+        file.encrypted_password = _encrypted_password;
+        file.last_update = env::block_timestamp();
+        file.update_by = _account_id;
+        self.files.insert(&_file_id, &file);
+        self.log_event("file_password_changed", json!({ "file_id": _file_id }));
+        self.charge_storage(initial_storage);
+    }
+
+    // `_updated_at` is still accepted so existing clients keep working, but
+    // `last_update` always holds the block time, like every other write.
+    #[payable]
+    pub fn update_file(
+        &mut self,
+        _folder_id: String,
+        _file_id: String,
+        _cid: String,
+        _updated_at: u64,
+    ) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        self.validate_cid(&_cid);
+        let mut file = self.get_writable_file(&_folder_id, &_file_id, &_account_id);
+        file.versions.push(FileVersion {
+            cid: file.cid,
+            updated_at: file.last_update,
+            updated_by: file.update_by,
+        });
+        if file.versions.len() > MAX_FILE_VERSIONS {
+            file.versions.remove(0);
+        }
+        file.cid = _cid;
+        file.last_update = env::block_timestamp();
+        file.update_by = _account_id;
+        self.files.insert(&_file_id, &file);
+        self.log_event(
+            "file_updated",
+            json!({ "file_id": _file_id, "folder_id": _folder_id }),
+        );
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn rename_folder(&mut self, _folder_id: String, _new_name: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        self.validate_name(&_new_name);
+        let _account_id = env::signer_account_id();
+        let (root_folder, folder_id) = self.find_root(_folder_id.clone());
+        self.verify_accessible(&root_folder, folder_id, _account_id);
+
+        match self.folders_v2.get(&_folder_id) {
+            Some(mut folder) => {
+                folder.name = _new_name;
+                touch_folder(&mut folder);
+                self.folders_v2.insert(&_folder_id, &folder);
+            }
+            None => env::panic(format!("Folder not found: '{}'", _folder_id).as_bytes()),
+        }
+        self.log_event("folder_renamed", json!({ "folder_id": _folder_id }));
+        self.charge_storage(initial_storage);
+    }
+
+    // Only changes the display order; the ids must be exactly the folder's
+    // current files.
+    #[payable]
+    pub fn reorder_files(&mut self, _folder_id: String, _ordered_ids: Vec<String>) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        self.assert_can_write(_folder_id.clone(), _account_id);
+
+        let mut folder = match self.folders_v2.get(&_folder_id) {
+            Some(folder) => folder,
+            None => env::panic(format!("Folder not found: '{}'", _folder_id).as_bytes()),
+        };
+        let mut current = folder.files.clone();
+        let mut ordered = _ordered_ids.clone();
+        current.sort();
+        ordered.sort();
+        assert!(
+            current == ordered,
+            "The new order must list exactly the files of folder {}",
+            &_folder_id
+        );
+        folder.files = _ordered_ids;
+        touch_folder(&mut folder);
+        self.folders_v2.insert(&_folder_id, &folder);
+        self.log_event("files_reordered", json!({ "folder_id": _folder_id }));
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn add_owner(&mut self, _folder_id: String, _owner: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        let mut root = self.get_owned_root(&_folder_id, &_account_id);
+        if !root.owners.contains(&_owner) {
+            root.owners.push(_owner.clone());
+            self.folders_v2.insert(&_folder_id, &root);
+            self.log_event(
+                "owner_added",
+                json!({ "folder_id": _folder_id, "owner": _owner }),
+            );
+        }
+        self.charge_storage(initial_storage);
+    }
+
+    // The account a root sits under stays its owner, so one always remains.
+    #[payable]
+    pub fn remove_owner(&mut self, _folder_id: String, _owner: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        let mut root = self.get_owned_root(&_folder_id, &_account_id);
+        assert_ne!(
+            self.account_of_root(&root.parent),
+            _owner,
+            "The primary owner can't be removed"
+        );
+        root.owners.retain(|owner| *owner != _owner);
+        self.folders_v2.insert(&_folder_id, &root);
+        self.log_event(
+            "owner_removed",
+            json!({ "folder_id": _folder_id, "owner": _owner }),
+        );
+        self.charge_storage(initial_storage);
+    }
+
+    fn get_owned_root(&self, folder_id: &str, account_id: &str) -> FolderV2 {
+        let (root_folder, root_folder_id) = self.find_root(folder_id.to_string());
+        assert_eq!(root_folder_id, folder_id, "this is not the root folder");
+        assert!(
+            self.is_root_owner(&root_folder, account_id),
+            "Only an owner can manage the owners of {}",
+            folder_id
+        );
+        root_folder.unwrap()
+    }
+
+    #[payable]
+    pub fn change_folder_password(&mut self, _folder_id: String, _new_password: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        assert!(
+            !_new_password.is_empty(),
+            "Shared folders require a password"
+        );
+        let _account_id = env::signer_account_id();
+        let (root_folder, _) = self.find_root(_folder_id.clone());
+        assert!(
+            self.is_root_owner(&root_folder, &_account_id),
+            "Only an owner can change the password of {}",
+            &_folder_id
+        );
+
+        match self.folders_v2.get(&_folder_id) {
+            Some(mut folder) => {
+                assert_eq!(
+                    folder.folder_type,
+                    Some(FolderKind::Shared),
+                    "Only shared folders have a password"
+                );
+                folder.folder_password = Some(_new_password);
+                self.folders_v2.insert(&_folder_id, &folder);
+                self.log_event(
+                    "folder_password_changed",
+                    json!({ "folder_id": _folder_id }),
+                );
+            }
+            None => env::panic(format!("Folder not found: '{}'", _folder_id).as_bytes()),
+        }
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn set_thumbnail(&mut self, _file_id: String, _thumbnail_cid: Option<String>) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        self.validate_file_media(&None, &_thumbnail_cid);
+        let _account_id = env::signer_account_id();
+        self.assert_can_write_doc(&_file_id, &_account_id);
+
+        match self.files.get(&_file_id) {
+            Some(mut file) => {
+                file.thumbnail_cid = _thumbnail_cid;
+                file.last_update = env::block_timestamp();
+                file.update_by = _account_id;
+                self.files.insert(&_file_id, &file);
+            }
+            None => env::panic(format!("File not found: '{}'", _file_id).as_bytes()),
+        }
+        self.log_event("thumbnail_set", json!({ "file_id": _file_id }));
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn set_file_description(&mut self, _file_id: String, _description: Option<String>) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        if let Some(description) = &_description {
+            assert!(
+                description.len() <= MAX_DESCRIPTION_LENGTH,
+                "Description can't be longer than {} bytes",
+                MAX_DESCRIPTION_LENGTH
+            );
+        }
+        let _account_id = env::signer_account_id();
+        self.assert_can_write_doc(&_file_id, &_account_id);
+
+        match self.files.get(&_file_id) {
+            Some(mut file) => {
+                file.description = _description;
+                file.last_update = env::block_timestamp();
+                file.update_by = _account_id;
+                self.files.insert(&_file_id, &file);
+            }
+            None => env::panic(format!("File not found: '{}'", _file_id).as_bytes()),
+        }
+        self.log_event("file_description_set", json!({ "file_id": _file_id }));
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn add_tag(&mut self, _doc_id: String, _tag: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let tag = self.normalize_tag(&_tag);
+        let _account_id = env::signer_account_id();
+        self.assert_can_write_doc(&_doc_id, &_account_id);
+
+        if let Some(mut file) = self.files.get(&_doc_id) {
+            push_tag(&mut file.tags, tag.clone());
+            self.files.insert(&_doc_id, &file);
+        } else if let Some(mut folder) = self.folders_v2.get(&_doc_id) {
+            push_tag(&mut folder.tags, tag.clone());
+            self.folders_v2.insert(&_doc_id, &folder);
+        }
+        self.log_event("tag_added", json!({ "doc_id": _doc_id, "tag": tag }));
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn remove_tag(&mut self, _doc_id: String, _tag: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let tag = self.normalize_tag(&_tag);
+        let _account_id = env::signer_account_id();
+        self.assert_can_write_doc(&_doc_id, &_account_id);
+
+        if let Some(mut file) = self.files.get(&_doc_id) {
+            file.tags.retain(|t| *t != tag);
+            self.files.insert(&_doc_id, &file);
+        } else if let Some(mut folder) = self.folders_v2.get(&_doc_id) {
+            folder.tags.retain(|t| *t != tag);
+            self.folders_v2.insert(&_doc_id, &folder);
+        }
+        self.log_event("tag_removed", json!({ "doc_id": _doc_id, "tag": tag }));
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn add_favorite(&mut self, _doc_id: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        assert!(
+            self.files.get(&_doc_id).is_some() || self.folders_v2.get(&_doc_id).is_some(),
+            "Doc not found: '{}'",
+            &_doc_id
+        );
+        assert!(
+            self.can_access(_doc_id.clone(), _account_id.clone())
+                .is_some(),
+            "You don't have access to {}",
+            &_doc_id
+        );
+        insert_into_set(&mut self.favorites, b"fav", &_account_id, &_doc_id);
+        self.log_event("favorite_added", json!({ "doc_id": _doc_id }));
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn remove_favorite(&mut self, _doc_id: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        remove_from_set(&mut self.favorites, &_account_id, &_doc_id);
+        self.log_event("favorite_removed", json!({ "doc_id": _doc_id }));
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn touch_file(&mut self, _file_id: String, _at: u64) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        assert!(
+            self.doc_permission(&_file_id, &_account_id).is_ok(),
+            "You don't have access to {}",
+            &_file_id
+        );
+        if let Some(mut file) = self.files.get(&_file_id) {
+            file.last_accessed = Some(or_block_time(_at));
+            self.files.insert(&_file_id, &file);
+        }
+        self.log_event("file_touched", json!({ "file_id": _file_id }));
+        self.charge_storage(initial_storage);
+    }
+
+    fn normalize_tag(&self, _tag: &str) -> String {
+        let tag = _tag.trim().to_lowercase();
+        self.validate_name(&tag);
+        tag
+    }
+
+    #[payable]
+    pub fn move_file(&mut self, _file_id: String, _from_folder: String, _to_folder: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        let (from_root, from_root_id) = self.find_root(_from_folder.clone());
+        self.verify_accessible(&from_root, from_root_id.clone(), _account_id.clone());
+        let (to_root, to_root_id) = self.find_root(_to_folder.clone());
+        self.verify_accessible(&to_root, to_root_id.clone(), _account_id.clone());
+
+        if from_root_id.ne(&to_root_id) {
+            assert!(
+                self.is_root_owner(&from_root, &_account_id)
+                    && self.is_root_owner(&to_root, &_account_id),
+                "Only owners can move files between roots"
+            );
+        }
+
+        match self.folders_v2.get(&_from_folder) {
+            Some(mut folder) => {
+                match folder.files.iter().position(|f| f.eq(&_file_id)) {
+                    Some(index) => {
+                        folder.files.remove(index);
+                    }
+                    None => env::panic(
+                        format!("file {} not found in folder {}", &_file_id, &_from_folder)
+                            .as_bytes(),
+                    ),
+                }
+                touch_folder(&mut folder);
+                self.folders_v2.insert(&_from_folder, &folder);
+            }
+            None => env::panic(format!("Folder not found: '{}'", _from_folder).as_bytes()),
+        }
+
+        match self.folders_v2.get(&_to_folder) {
+            Some(mut folder) => {
+                if !folder.files.contains(&_file_id) {
+                    self.assert_folder_has_room(&folder, &_to_folder);
+                    folder.files.push(_file_id.clone());
+                }
+                self.file_parent.insert(&_file_id, &_to_folder);
+                touch_folder(&mut folder);
+                self.folders_v2.insert(&_to_folder, &folder);
+            }
+            None => env::panic(format!("Folder not found: '{}'", _to_folder).as_bytes()),
+        }
+        self.log_event(
+            "file_moved",
+            json!({ "file_id": _file_id, "from": _from_folder, "to": _to_folder }),
+        );
+        self.charge_storage(initial_storage);
+    }
+
+    // Moving within one tree needs write access to it; moving between trees,
+    // or to the top of the signer's drive, needs ownership of both sides.
+    #[payable]
+    pub fn move_folder(&mut self, _folder_id: String, _new_parent: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        let mut folder = match self.folders_v2.get(&_folder_id) {
+            Some(folder) => folder,
+            None => env::panic(format!("Folder not found: '{}'", _folder_id).as_bytes()),
+        };
+        assert_ne!(&folder.parent, &_folder_id, "root folder can't be moved");
+        let new_parent_folder = match self.folders_v2.get(&_new_parent) {
+            Some(folder) => folder,
+            None => env::panic(format!("Folder not found: '{}'", _new_parent).as_bytes()),
+        };
+
+        let (root_folder, root_folder_id) = self.find_root(_folder_id.clone());
+        self.verify_accessible(&root_folder, root_folder_id.clone(), _account_id.clone());
+        let to_top = new_parent_folder.parent == _new_parent;
+        if to_top {
+            assert_eq!(
+                _new_parent,
+                self.root_id_of(&_account_id),
+                "Folders can only be moved to the top of your own drive"
+            );
+            assert!(
+                self.is_root_owner(&root_folder, &_account_id),
+                "Only owners can move folders between roots"
+            );
+        } else {
+            let (new_root, new_root_id) = self.find_root(_new_parent.clone());
+            self.verify_accessible(&new_root, new_root_id.clone(), _account_id.clone());
+            if root_folder_id != new_root_id {
+                assert!(
+                    self.is_root_owner(&root_folder, &_account_id)
+                        && self.is_root_owner(&new_root, &_account_id),
+                    "Only owners can move folders between roots"
+                );
+            }
+        }
+
+        let path = self.get_path(_new_parent.clone());
+        assert!(
+            path.len() < MAX_ANCESTOR_HOPS,
+            "Ancestors of '{}' form a cycle",
+            &_new_parent
+        );
+        assert!(
+            path.iter().all(|(id, _)| *id != _folder_id),
+            "can't move folder {} into its own subtree",
+            &_folder_id
+        );
+        // Same rule as `create_folder_v2`, applied to the deepest moved folder.
+        assert!(
+            path.len() + self.subtree_height(&_folder_id) <= self.max_depth as usize,
+            "Folders can't be nested more than {} levels deep",
+            self.max_depth
+        );
+
+        if folder.parent.eq(&_new_parent) {
+            self.charge_storage(initial_storage);
+            return;
+        }
+        let old_parent = folder.parent.clone();
+        self.remove_child(&old_parent, &_folder_id);
+
+        let mut new_parent = new_parent_folder;
+        new_parent.children.push(_folder_id.clone());
+        touch_folder(&mut new_parent);
+        self.folders_v2.insert(&_new_parent, &new_parent);
+
+        folder.parent = _new_parent;
+        // Co-owners only mean something on a root, and whoever moves a folder to
+        // the top of their drive becomes its only owner.
+        folder.owners = if to_top {
+            vec![_account_id]
+        } else {
+            Vec::new()
+        };
+        touch_folder(&mut folder);
+        self.folders_v2.insert(&_folder_id, &folder);
+        self.log_event(
+            "folder_moved",
+            json!({ "folder_id": _folder_id, "from": old_parent, "to": folder.parent }),
+        );
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn remove_file_v2(&mut self, _folder_id: String, _file_id: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        let (root_folder, root_folder_id) = self.find_root(_folder_id.clone());
+        self.verify_accessible(&root_folder, root_folder_id, _account_id.clone());
+        let owner_id = self.root_owner(&root_folder).unwrap();
+        match self.folders_v2.get(&_folder_id) {
+            Some(mut folder) => match folder.files.iter().position(|f| f.eq(&_file_id)) {
+                Some(index) => {
+                    folder.files.remove(index);
+                    touch_folder(&mut folder);
+                    self.folders_v2.insert(&_folder_id, &folder);
+                    self.file_parent.remove(&_file_id);
+                    self.trash_item(&_file_id, DocType::File, &_folder_id, &owner_id);
+                    self.remove_shares_where(&owner_id, |_, share_doc| {
+                        share_doc.doc_id == _file_id
+                    });
+                    self.log_event(
+                        "file_removed",
+                        json!({ "file_id": _file_id, "folder_id": _folder_id }),
+                    );
+                }
+                None => {
+                    env::log(
+                        format!("File '{}' not found in folder '{}'", _file_id, _folder_id)
+                            .as_bytes(),
+                    );
+                }
+            },
+            None => {
+                env::log(format!("Folder not found: '{}'", _folder_id).as_bytes());
+            }
+        }
+        self.charge_storage(initial_storage);
+    }
+
+    // Moves every listed file that is in the folder to the trash and returns
+    // the ids that were actually removed.
+    #[payable]
+    pub fn remove_files_v2(&mut self, _folder_id: String, _file_ids: Vec<String>) -> Vec<String> {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        assert!(
+            _file_ids.len() <= MAX_NODES_PER_CALL,
+            "Can't remove more than {} files in one call",
+            MAX_NODES_PER_CALL
+        );
+        let _account_id = env::signer_account_id();
+        let (root_folder, root_folder_id) = self.find_root(_folder_id.clone());
+        self.verify_accessible(&root_folder, root_folder_id, _account_id.clone());
+        let owner_id = self.root_owner(&root_folder).unwrap();
+        let mut folder = match self.folders_v2.get(&_folder_id) {
+            Some(folder) => folder,
+            None => env::panic(format!("Folder not found: '{}'", _folder_id).as_bytes()),
+        };
+
+        let mut removed = Vec::new();
+        for file_id in _file_ids {
+            if let Some(index) = folder.files.iter().position(|f| f.eq(&file_id)) {
+                folder.files.remove(index);
+                self.file_parent.remove(&file_id);
+                self.trash_item(&file_id, DocType::File, &_folder_id, &owner_id);
+                self.log_event(
+                    "file_removed",
+                    json!({ "file_id": file_id, "folder_id": _folder_id }),
+                );
+                removed.push(file_id);
+            }
+        }
+        let removed_ids: HashSet<&String> = removed.iter().collect();
+        self.remove_shares_where(&owner_id, |_, share_doc| {
+            removed_ids.contains(&share_doc.doc_id)
+        });
+        touch_folder(&mut folder);
+        self.folders_v2.insert(&_folder_id, &folder);
+        self.charge_storage(initial_storage);
+        removed
+    }
+
+    #[payable]
+    pub fn remove_folder_v2(&mut self, _folder_id: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        let (root_folder, root_folder_id) = self.find_root(_folder_id.clone());
+        self.verify_accessible(&root_folder, root_folder_id, _account_id.clone());
+        let owner_id = self.root_owner(&root_folder).unwrap();
+
+        match self.folders_v2.get(&_folder_id) {
+            Some(folder) => {
+                assert_ne!(&folder.parent, &_folder_id, "root folder can't be removed");
+                self.remove_child(&folder.parent, &_folder_id);
+                self.trash_item(&_folder_id, DocType::Folder, &folder.parent, &owner_id);
+                self.remove_shares_where(&owner_id, |contract, share_doc| {
+                    contract.doc_is_within(&share_doc.doc_id, share_doc.doc_type, &_folder_id)
+                });
+                self.log_event("folder_removed", json!({ "folder_id": _folder_id }));
+            }
+            None => {
+                env::log(format!("Folder not found: '{}'", _folder_id).as_bytes());
+            }
+        }
+        self.charge_storage(initial_storage);
+    }
+
+    #[payable]
+    pub fn restore_item(&mut self, _id: String) {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        let item = self.get_trashed_item(&_id, &_account_id);
+        match self.folders_v2.get(&item.parent) {
+            Some(mut parent) => {
+                if item.item_type == DocType::File {
+                    parent.files.push(_id.clone());
+                    self.file_parent.insert(&_id, &item.parent);
+                } else {
+                    parent.children.push(_id.clone());
+                }
+                touch_folder(&mut parent);
+                self.folders_v2.insert(&item.parent, &parent);
+            }
+            None => {
+                env::panic(format!("Original folder '{}' no longer exists", item.parent).as_bytes())
+            }
+        }
+        self.untrash_item(&_id, &_account_id);
+        self.log_event("item_restored", json!({ "id": _id, "parent": item.parent }));
+        self.charge_storage(initial_storage);
+    }
+
+    // Folders are purged together with their subtree; returns false when the
+    // caller has to call again to finish.
+    #[payable]
+    pub fn purge_item(&mut self, _id: String) -> bool {
+        self.assert_not_paused();
+        let initial_storage = env::storage_usage();
+        let _account_id = env::signer_account_id();
+        let item = self.get_trashed_item(&_id, &_account_id);
+        if item.item_type == DocType::File {
+            self.delete_file(&_id);
+        } else if !self.remove_folder_tree(_id.clone()) {
+            self.charge_storage(initial_storage);
+            return false;
+        }
+        self.untrash_item(&_id, &_account_id);
+        self.log_event("item_purged", json!({ "id": _id }));
+        self.charge_storage(initial_storage);
+        true
+    }
+
+    // Maintenance only: checks at most `limit` entries of `files` from
+    // `from_index` on and returns the orphans among them, along with the index
+    // to continue from. Files in the trash aren't orphans.
+    pub fn find_orphaned_files(
+        &self,
+        account_id: String,
+        from_index: u64,
+        limit: u64,
+    ) -> (Vec<String>, Option<u64>) {
+        let keys = self.files.keys_as_vector();
+        let values = self.files.values_as_vector();
+        let limit = std::cmp::min(limit, MAX_NODES_PER_CALL as u64);
+        let to_index = std::cmp::min(from_index.saturating_add(limit), keys.len());
+        let mut reachable = HashMap::new();
+        let orphans = (from_index..to_index)
+            .filter_map(|index| {
+                let file_id = keys.get(index).unwrap();
+                let file = values.get(index).unwrap();
+                if file.created_by == account_id
+                    && self.is_orphaned(&file_id, &file.created_by, &mut reachable)
+                {
+                    Some(file_id)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let next_index = if to_index < keys.len() {
+            Some(to_index)
+        } else {
+            None
+        };
+        (orphans, next_index)
+    }
+
+    // Each id is checked again before it is deleted; returns the ids that were
+    // actually purged.
+    #[payable]
+    pub fn purge_orphaned_files(&mut self, ids: Vec<String>) -> Vec<String> {
+        self.assert_owner();
+        let initial_storage = env::storage_usage();
+        assert!(
+            ids.len() <= MAX_NODES_PER_CALL,
+            "Can't purge more than {} files in one call",
+            MAX_NODES_PER_CALL
+        );
+        let mut reachable = HashMap::new();
+        let mut purged = Vec::new();
+        for file_id in ids {
+            let is_orphaned = match self.files.get(&file_id) {
+                Some(file) => self.is_orphaned(&file_id, &file.created_by, &mut reachable),
+                None => false,
+            };
+            if is_orphaned {
+                self.delete_file(&file_id);
+                purged.push(file_id);
+            }
+        }
+        self.log_event("orphaned_files_purged", json!({ "file_ids": purged }));
+        self.charge_storage(initial_storage);
+        purged
+    }
+
+    // A file is referenced by the folder `file_parent` points at. Files stored
+    // before that index existed are looked for in the folders their creator can
+    // reach, walked once per creator; if those are too many, the file is kept.
+    fn is_orphaned(
+        &self,
+        file_id: &String,
+        created_by: &String,
+        reachable: &mut HashMap<String, Option<HashSet<String>>>,
+    ) -> bool {
+        if self.trashed.get(file_id).is_some() {
+            return false;
+        }
+        if let Some(folder_id) = self.file_parent.get(file_id) {
+            return !self
+                .folders_v2
+                .get(&folder_id)
+                .is_some_and(|folder| folder.files.contains(file_id));
+        }
+        match reachable
+            .entry(created_by.clone())
+            .or_insert_with(|| self.reachable_files(created_by))
+        {
+            Some(files) => !files.contains(file_id),
+            None => false,
+        }
+    }
+
+    // Files in the account's drive and in the folders shared with it, or None
+    // when that is more than MAX_NODES_PER_CALL folders.
+    fn reachable_files(&self, account_id: &String) -> Option<HashSet<String>> {
+        let mut roots = vec![self.root_id_of(account_id)];
+        if let Some(share_ids) = self.shared_doc_of_user.get(account_id) {
+            if share_ids.len() > MAX_NODES_PER_CALL as u64 {
+                return None;
+            }
+            roots.extend(
+                share_ids
+                    .iter()
+                    .filter_map(|share_id| self.shared_docs.get(&share_id))
+                    .filter(|share_doc| share_doc.doc_type == DocType::Folder)
+                    .map(|share_doc| share_doc.doc_id),
+            );
+        }
+        let mut files = HashSet::new();
+        let mut budget = MAX_NODES_PER_CALL;
+        for root in roots {
+            let (folders, truncated) = self.walk_subtree(root, budget);
+            if truncated {
+                return None;
+            }
+            budget -= folders.len();
+            files.extend(folders.into_iter().flat_map(|(_, folder)| folder.files));
+        }
+        Some(files)
+    }
+
+    pub fn get_trash(&self, account_id: String) -> Vec<TrashedItem> {
+        match self.trash_of_user.get(&account_id) {
+            Some(trash) => trash
+                .iter()
+                .filter_map(|id| self.trashed.get(&id))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    fn get_trashed_item(&self, id: &String, account_id: &str) -> TrashedItem {
+        match self.trashed.get(id) {
+            Some(item) => {
+                assert_eq!(item.owner, account_id, "You don't own trashed item {}", id);
+                item
+            }
+            None => env::panic(format!("Item not found in trash: '{}'", id).as_bytes()),
+        }
+    }
+
+    fn trash_item(&mut self, id: &String, item_type: DocType, parent: &str, owner: &String) {
+        let item = TrashedItem {
+            id: id.clone(),
+            item_type,
+            parent: parent.to_string(),
+            owner: owner.clone(),
+            deleted_at: env::block_timestamp(),
+        };
+        self.trashed.insert(id, &item);
+        insert_into_set(&mut self.trash_of_user, b"trou", owner, id);
+    }
+
+    // Removed docs lose their shares right away, so recipients don't keep
+    // access to the trash; restoring a doc doesn't bring them back. Drops every
+    // share of `owner_id` that `matches`, in one pass over the owner's shares.
+    fn remove_shares_where<F: Fn(&Self, &ShareDoc) -> bool>(
+        &mut self,
+        owner_id: &String,
+        matches: F,
+    ) {
+        let shares: Vec<(String, ShareDoc)> = match self.shared_by_user.get(owner_id) {
+            Some(share_ids) => share_ids
+                .iter()
+                .filter_map(|share_id| {
+                    let share_doc = self.shared_docs.get(&share_id)?;
+                    if matches(self, &share_doc) {
+                        Some((share_id, share_doc))
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+            None => return,
+        };
+        for (share_id, share_doc) in shares {
+            self.drop_share(&share_id, &share_doc, owner_id);
+        }
+    }
+
+    // Whether the doc is `folder_id` or sits anywhere below it. Files missing
+    // from `file_parent` can't be placed and count as outside.
+    fn doc_is_within(&self, doc_id: &String, doc_type: DocType, folder_id: &str) -> bool {
+        let start = match doc_type {
+            DocType::File => match self.file_parent.get(doc_id) {
+                Some(parent) => parent,
+                None => return false,
+            },
+            DocType::Folder => doc_id.clone(),
+        };
+        self.get_path(start).iter().any(|(id, _)| id == folder_id)
+    }
+
+    fn untrash_item(&mut self, id: &String, owner: &String) {
+        self.trashed.remove(id);
+        remove_from_set(&mut self.trash_of_user, owner, id);
+    }
+
+    // Deletes deepest folders first so a call that runs out of budget leaves a
+    // consistent tree. Returns false when the caller has to call again.
+    fn remove_folder_tree(&mut self, folder_id: String) -> bool {
+        let mut processed: usize = 0;
+        let mut stack = vec![folder_id];
+        while let Some(current_id) = stack.last().cloned() {
+            if processed >= MAX_NODES_PER_CALL {
+                return false;
+            }
+            match self.folders_v2.get(&current_id) {
+                Some(mut folder) => {
+                    if let Some(child_id) = folder.children.last() {
+                        stack.push(child_id.clone());
+                        continue;
+                    }
+
+                    let budget = MAX_NODES_PER_CALL - processed;
+                    if folder.files.len() >= budget {
+                        for file_id in folder.files.drain(..budget) {
+                            self.delete_file(&file_id);
+                        }
+                        self.folders_v2.insert(&current_id, &folder);
+                        return false;
+                    }
+                    for file_id in folder.files.iter() {
+                        self.delete_file(file_id);
+                    }
+                    processed += folder.files.len() + 1;
+
+                    self.folders_v2.remove(&current_id);
+                    if folder.parent != current_id {
+                        self.update_stats(&folder.created_by, 0, -1);
+                    }
+                    self.remove_child(&folder.parent, &current_id);
+                    stack.pop();
+                }
+                None => {
+                    // The parent references a folder that no longer exists.
+                    stack.pop();
+                    if let Some(parent_id) = stack.last() {
+                        self.remove_child(parent_id, &current_id);
+                    }
+                    processed += 1;
+                }
+            }
+        }
+        true
+    }
+
+    // Rewrites references to `from` in a folder and its files. Only nodes
+    // pointing at `from` change, so a cursor can't touch other users' data.
+    // With `check_quota`, the files `to` takes over have to fit in its quota.
+    fn transfer_folder(
+        &mut self,
+        folder_id: &str,
+        folder: &mut FolderV2,
+        from: &str,
+        to: &str,
+        check_quota: bool,
+    ) -> usize {
+        // Roots keyed by the account are their own parent and move along.
+        if folder.parent == from || folder.parent == self.root_id_of(from) {
+            folder.parent = if folder_id == to {
+                to.to_string()
+            } else {
+                self.root_id_of(to)
+            };
+        }
+        for owner in folder.owners.iter_mut() {
+            if owner == from {
+                *owner = to.to_string();
+            }
+        }
+        if folder.created_by == from {
+            folder.created_by = to.to_string();
+            if folder.parent != folder_id {
+                self.update_stats(from, 0, -1);
+                self.update_stats(to, 0, 1);
+            }
+        }
+        for file_id in folder.files.iter() {
+            if let Some(mut file) = self.files.get(file_id) {
+                if file.created_by == from {
+                    file.created_by = to.to_string();
+                    self.files.insert(file_id, &file);
+
+                    let from_used = self.usage.get(&from.to_string()).unwrap_or(0);
+                    self.usage
+                        .insert(&from.to_string(), &from_used.saturating_sub(file.size));
+                    if check_quota {
+                        self.consume_quota(&to.to_string(), file.size);
+                    } else {
+                        let to_used = self.usage.get(&to.to_string()).unwrap_or(0);
+                        self.usage.insert(&to.to_string(), &(to_used + file.size));
+                    }
+                    self.update_stats(from, -1, 0);
+                    self.update_stats(to, 1, 0);
+                }
+            }
+        }
+        folder.files.len()
+    }
+
+    // Moves up to `budget` of the shares keyed by `from` over to the ids `to`
+    // would have created them under. Returns how many were looked at.
+    fn rekey_shares(&mut self, from: &String, to: &String, budget: usize) -> usize {
+        let share_ids: Vec<String> = match self.shared_by_user.get(from) {
+            Some(share_ids) => share_ids.iter().take(budget).collect(),
+            None => return 0,
+        };
+        for share_id in share_ids.iter() {
+            let mut share_doc = match self.shared_docs.get(share_id) {
+                Some(share_doc) => share_doc,
+                None => {
+                    remove_from_set(&mut self.shared_by_user, from, share_id);
+                    continue;
+                }
+            };
+            let shared_with = share_recipient(share_id, &share_doc, from);
+            self.drop_share(share_id, &share_doc, from);
+            if let Some(shared_with) = shared_with {
+                share_doc.shared_with = shared_with.clone();
+                if share_doc.shared_by == *from {
+                    share_doc.shared_by = to.clone();
+                }
+                let new_id = share_doc_id(to, &shared_with, &share_doc.doc_id);
+                if self.shared_docs.insert(&new_id, &share_doc).is_none() {
+                    self.update_share_count(&share_doc.doc_id, 1);
+                }
+                insert_into_set(&mut self.shared_doc_of_user, b"sdou", &shared_with, &new_id);
+                insert_into_set(&mut self.shared_by_user, b"sdbu", to, &new_id);
+            }
+        }
+        share_ids.len()
+    }
+
+    fn assert_own_top_level(&self, folder_id: &String, account_id: &str) {
+        let folder = match self.folders_v2.get(folder_id) {
+            Some(folder) => folder,
+            None => env::panic(format!("Folder not found: '{}'", folder_id).as_bytes()),
+        };
+        assert!(
+            *folder_id != folder.parent && folder.parent == self.root_id_of(account_id),
+            "Only a top-level folder of your own can be gifted"
+        );
+    }
+
+    fn remove_child(&mut self, parent_id: &str, child_id: &str) {
+        if let Some(mut parent_folder) = self.folders_v2.get(&parent_id.to_string()) {
+            if let Some(index) = parent_folder.children.iter().position(|f| f == child_id) {
+                parent_folder.children.remove(index);
+                touch_folder(&mut parent_folder);
+                self.folders_v2
+                    .insert(&parent_id.to_string(), &parent_folder);
+            }
+        }
+    }
+
+    // Loads a file after checking `account_id` can write to `folder_id` and
+    // that the file is stored in it.
+    fn get_writable_file(&self, folder_id: &String, file_id: &String, account_id: &str) -> File {
+        let (root_folder, root_folder_id) = self.find_root(folder_id.clone());
+        self.verify_accessible(&root_folder, root_folder_id, account_id.to_string());
+
+        match self.folders_v2.get(folder_id) {
+            Some(folder) => {
+                assert!(
+                    folder.files.contains(file_id),
+                    "file {} not found in folder {}",
+                    file_id,
+                    folder_id
+                );
+            }
+            None => env::panic(format!("Folder not found: '{}'", folder_id).as_bytes()),
+        }
+
+        match self.files.get(file_id) {
+            Some(file) => file,
+            None => env::panic(format!("File not found: '{}'", file_id).as_bytes()),
+        }
+    }
+
+    fn consume_quota(&mut self, account_id: &String, size: u64) {
+        let (used, limit) = self.get_usage(account_id.clone());
+        assert!(
+            used.saturating_add(size) <= limit,
+            "Storage quota exceeded: {} of {} bytes used",
+            used,
+            limit
+        );
+        self.usage.insert(account_id, &(used + size));
+    }
+
+    fn delete_file(&mut self, file_id: &String) {
+        self.file_parent.remove(file_id);
+        if let Some(file) = self.files.remove(file_id) {
+            let used = self.usage.get(&file.created_by).unwrap_or(0);
+            self.usage
+                .insert(&file.created_by, &used.saturating_sub(file.size));
+            self.update_stats(&file.created_by, -1, 0);
+        }
+    }
+
+    // Counts every file and folder an account created, including the ones in
+    // trash. The account root is not counted.
+    fn update_stats(&mut self, account_id: &str, file_delta: i64, folder_delta: i64) {
+        let account_id = account_id.to_string();
+        let mut stats = self.stats.get(&account_id).unwrap_or_default();
+        stats.file_count = (stats.file_count as i64 + file_delta).max(0) as u64;
+        stats.folder_count = (stats.folder_count as i64 + folder_delta).max(0) as u64;
+        self.stats.insert(&account_id, &stats);
+    }
+
+    // Emits the event and records it in the signer's activity log. The target
+    // is the first id the event carries, or the signer for account-level events.
+    fn log_event(&mut self, event: &str, data: Value) {
+        let actor = env::signer_account_id();
+        let target = ["file_id", "folder_id", "doc_id", "id"]
+            .iter()
+            .find_map(|key| data[*key].as_str().map(|id| id.to_string()))
+            .unwrap_or_else(|| actor.clone());
+        let mut log = self.activity.get(&actor).unwrap_or_else(|| {
+            let mut prefix = b"act".to_vec();
+            prefix.extend(env::sha256(actor.as_bytes()));
+            ActivityLog {
+                entries: Vector::new(prefix),
+                written: 0,
+            }
+        });
+        let entry = ActivityEntry {
+            action: event.to_string(),
+            target,
+            timestamp: env::block_timestamp(),
+            actor: actor.clone(),
+        };
+        if log.entries.len() < MAX_ACTIVITY_ENTRIES {
+            log.entries.push(&entry);
+        } else {
+            log.entries
+                .replace(log.written % MAX_ACTIVITY_ENTRIES, &entry);
+        }
+        log.written += 1;
+        self.activity.insert(&actor, &log);
+        emit_event(event, data);
+    }
+
+    // Remembers the signer's last MAX_REQUEST_IDS request ids. A retried call
+    // with a known id is then skipped instead of failing on the first
+    // attempt's result or creating its doc twice.
+    fn is_replayed(&mut self, request_id: &Option<String>) -> bool {
+        let request_id = match request_id {
+            Some(request_id) => request_id,
+            None => return false,
+        };
+        let account_id = env::signer_account_id();
+        let mut seen = self.seen_requests.get(&account_id).unwrap_or_default();
+        if seen.contains(request_id) {
+            env::log(format!("Request {} was already handled", request_id).as_bytes());
+            return true;
+        }
+        if seen.len() >= MAX_REQUEST_IDS {
+            seen.remove(0);
+        }
+        seen.push(request_id.clone());
+        self.seen_requests.insert(&account_id, &seen);
+        false
+    }
+
+    // Removes a share from `shared_docs` and both indexes. Shares still stored
+    // under a legacy id were never counted, so only hashed ids lower the count.
+    fn drop_share(&mut self, share_doc_id: &String, share_doc: &ShareDoc, owner_id: &String) {
+        self.shared_docs.remove(share_doc_id);
+        if let Some(shared_with) = share_recipient(share_doc_id, share_doc, owner_id) {
+            remove_from_set(&mut self.shared_doc_of_user, &shared_with, share_doc_id);
+        }
+        remove_from_set(&mut self.shared_by_user, owner_id, share_doc_id);
+        if is_hashed_share_id(share_doc_id) {
+            self.update_share_count(&share_doc.doc_id, -1);
+        }
+    }
+
+    fn update_share_count(&mut self, doc_id: &String, delta: i64) {
+        let count = (self.share_counts.get(doc_id).unwrap_or(0) as i64 + delta).max(0) as u64;
+        if count == 0 {
+            self.share_counts.remove(doc_id);
+        } else {
+            self.share_counts.insert(doc_id, &count);
+        }
+    }
+
+    // Levels below `folder_id`, 0 for a folder without subfolders. Panics
+    // rather than guess when the subtree has more than MAX_VIEW_NODES folders.
+    fn subtree_height(&self, folder_id: &str) -> usize {
+        let mut height = 0;
+        let mut visited: usize = 0;
+        let mut level = vec![folder_id.to_string()];
+        loop {
+            let mut next = Vec::new();
+            for current_id in level {
+                visited += 1;
+                assert!(
+                    visited <= MAX_VIEW_NODES,
+                    "Folder {} has more than {} subfolders to check",
+                    folder_id,
+                    MAX_VIEW_NODES
+                );
+                if let Some(folder) = self.folders_v2.get(&current_id) {
+                    next.extend(folder.children);
+                }
+            }
+            if next.is_empty() {
+                return height;
+            }
+            height += 1;
+            level = next;
+        }
+    }
+
+    // Breadth-first list of `folder_id` and its descendants, at most `max_nodes`
+    // long. The flag is true when folders were left out.
+    fn walk_subtree(&self, folder_id: String, max_nodes: usize) -> (Vec<(String, FolderV2)>, bool) {
+        let mut result = Vec::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(folder_id);
+        while let Some(current_id) = queue.pop_front() {
+            if result.len() >= max_nodes {
+                return (result, true);
+            }
+            if let Some(folder) = self.folders_v2.get(&current_id) {
+                queue.extend(folder.children.iter().cloned());
+                result.push((current_id, folder));
+            }
+        }
+        (result, false)
+    }
+
+    // Charges the signer for the bytes written since `initial_storage`, credits
+    // it for bytes freed, and refunds whatever part of the attached deposit is
+    // left over. Every write is attributed to the signer, so the balance and
+    // the refund are kept on that one account as well.
+    fn charge_storage(&mut self, initial_storage: StorageUsage) {
+        let account_id = env::signer_account_id();
+        let final_storage = env::storage_usage();
+        let required_bytes = final_storage.saturating_sub(initial_storage);
+        let freed_bytes = initial_storage.saturating_sub(final_storage);
+        if required_bytes > 0 || freed_bytes > 0 {
+            let used = self.storage_used.get(&account_id).unwrap_or(0);
+            self.storage_used.insert(
+                &account_id,
+                &(used + required_bytes).saturating_sub(freed_bytes),
+            );
+        }
+
+        let required = Balance::from(required_bytes) * env::storage_byte_cost();
+        let attached = env::attached_deposit();
+        assert!(
+            attached >= required,
+            "Must attach {} yoctoNEAR to cover storage",
+            required
+        );
+        let refund = attached - required;
+        if refund > 0 {
+            Promise::new(account_id).transfer(refund);
+        }
+    }
+
+    pub fn get_user(&self, account_id: String) -> Option<PublicUser> {
+        env::log(format!("Account : '{}'", account_id).as_bytes());
+        self.users.get(&account_id).map(PublicUser::from)
+    }
+
+    // Has to be called as a transaction: views can't tell who is calling.
+    pub fn get_own_user(&self) -> Option<User> {
+        self.users.get(&env::predecessor_account_id())
+    }
+
+    // The unredacted folder, for owners of its root only. Also has to be called
+    // as a transaction.
+    pub fn get_own_folder(&self, folder_id: String) -> Option<FolderV2> {
+        let (root_folder, _) = self.find_root(folder_id.clone());
+        assert!(
+            self.is_root_owner(&root_folder, &env::predecessor_account_id()),
+            "Only owners can read the full folder {}",
+            &folder_id
+        );
+        self.folders_v2.get(&folder_id)
+    }
+
+    pub fn is_registered(&self, account_id: String) -> bool {
+        self.users.get(&account_id).is_some()
+    }
+
+    pub fn get_users(&self, from_index: u64, limit: u64) -> Vec<(String, PublicUser)> {
+        let keys = self.users.keys_as_vector();
+        let values = self.users.values_as_vector();
+        let to_index = std::cmp::min(from_index.saturating_add(limit), keys.len());
+        (from_index..to_index)
+            .map(|index| (keys.get(index).unwrap(), values.get(index).unwrap().into()))
+            .collect()
+    }
+
+    pub fn get_user_count(&self) -> u64 {
+        self.users.len()
+    }
+
+    pub fn storage_balance_of(&self, account_id: String) -> U128 {
+        let used = self.storage_used.get(&account_id).unwrap_or(0);
+        U128(Balance::from(used) * env::storage_byte_cost())
+    }
+
+    // Rough deposit for `create_file_v2`. Strings it isn't told about are
+    // assumed to be ESTIMATED_STRING_LENGTH bytes, and the records an account's
+    // first file creates (usage, stats, activity log, request ids) are always
+    // counted. Any excess deposit is refunded, so clients can attach this as is.
+    // Keep it in step with what `insert_file_v2` and `log_event` write.
+    pub fn estimate_file_cost(&self, name_len: u32, cid_len: u32, has_password: bool) -> U128 {
+        let unknown = "x".repeat(ESTIMATED_STRING_LENGTH);
+        let file = File {
+            cid: "x".repeat(cid_len as usize),
+            name: "x".repeat(name_len as usize),
+            encrypted_password: if has_password {
+                Some(unknown.clone())
+            } else {
+                None
+            },
+            file_type: unknown.clone(),
+            last_update: 0,
+            update_by: unknown.clone(),
+            created_at: 0,
+            created_by: unknown.clone(),
+            size: 0,
+            versions: Vec::new(),
+            tags: Vec::new(),
+            last_accessed: None,
+            block_time: 0,
+            description: None,
+            mime: None,
+            thumbnail_cid: None,
+        };
+        let entry = ActivityEntry {
+            action: unknown.clone(),
+            target: unknown.clone(),
+            timestamp: 0,
+            actor: unknown.clone(),
+        };
+        let file_len = file.try_to_vec().unwrap_or_default().len();
+        let entry_len = entry.try_to_vec().unwrap_or_default().len();
+        let id_len = unknown.try_to_vec().unwrap_or_default().len();
+
+        // The file itself, its `file_parent` record and its id in the folder's
+        // file list.
+        let mut bytes = map_entry_len(id_len, file_len) + map_entry_len(id_len, id_len) + id_len;
+        // One activity entry, keyed by the log's prefix and its index.
+        bytes += STORAGE_RECORD_OVERHEAD + (3 + 32 + 8) + entry_len;
+        // `usage`, `stats` and `storage_used` entries, the activity log header
+        // and the request ids.
+        bytes += 2 * map_entry_len(id_len, 8) + map_entry_len(id_len, 16);
+        bytes += map_entry_len(id_len, 4 + 3 + 32 + 8 + 8);
+        bytes += map_entry_len(id_len, 4) + id_len;
+        U128(Balance::from(bytes as u64) * env::storage_byte_cost())
+    }
+
+    pub fn set_quota(&mut self, account_id: String, limit: u64) {
+        self.assert_owner();
+        self.quotas.insert(&account_id, &limit);
+    }
+
+    pub fn set_default_quota(&mut self, limit: u64) {
+        self.assert_owner();
+        self.default_quota = limit;
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: u32) {
+        self.assert_owner();
+        self.max_depth = max_depth;
+    }
+
+    pub fn get_max_depth(&self) -> u32 {
+        self.max_depth
+    }
+
+    pub fn set_max_files_per_folder(&mut self, max_files: u32) {
+        self.assert_owner();
+        self.max_files_per_folder = max_files;
+    }
+
+    pub fn get_max_files_per_folder(&self) -> u32 {
+        self.max_files_per_folder
+    }
+
+    pub fn get_usage(&self, account_id: String) -> (u64, u64) {
+        let used = self.usage.get(&account_id).unwrap_or(0);
+        let limit = self.quotas.get(&account_id).unwrap_or(self.default_quota);
+        (used, limit)
+    }
+
+    pub fn get_shared_doc_of_user(&self, _account_id:String) -> Vec<String> {
+        match self.shared_doc_of_user.get(&_account_id) {
+            Some(shared_docs) => {
+                shared_docs.iter().collect()
+            },
+            None => vec![]
+        }
+    }
+
+    // The account's own root plus every live folder share it has received.
+    pub fn get_accessible_roots(&self, account_id: String) -> Vec<(String, u8)> {
+        let mut roots = Vec::new();
+        let root_id = self.root_id_of(&account_id);
+        if self.folders_v2.get(&root_id).is_some() {
+            roots.push((root_id, u8::from(Permission::Write)));
+        }
+        if let Some(share_ids) = self.shared_doc_of_user.get(&account_id) {
+            for share_id in share_ids.iter() {
+                if let Some(share_doc) = self.shared_docs.get(&share_id) {
+                    if share_doc.doc_type == DocType::Folder
+                        && !share_doc.is_expired()
+                        && self.folders_v2.get(&share_doc.doc_id).is_some()
+                    {
+                        roots.push((share_doc.doc_id, u8::from(share_doc.permission)));
+                    }
+                }
+            }
+        }
+        roots
+    }
+
+    pub fn get_favorites(&self, account_id: String) -> Vec<String> {
+        match self.favorites.get(&account_id) {
+            Some(favorites) => favorites.to_vec(),
+            None => vec![],
+        }
+    }
+
+    // Clients should use this rather than rebuilding share ids themselves.
+    pub fn compute_share_id(&self, owner: String, shared_with: String, doc_id: String) -> String {
+        share_doc_id(&owner, &shared_with, &doc_id)
+    }
+
+    pub fn get_shared_by_me(&self, _account_id: String) -> Vec<(String, ShareDoc)> {
+        match self.shared_by_user.get(&_account_id) {
+            Some(share_ids) => share_ids
+                .iter()
+                .filter_map(|share_id| {
+                    self.shared_docs
+                        .get(&share_id)
+                        .map(|share_doc| (share_id, redact_share(share_doc)))
+                })
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    // Views can't read the signer, so the owner is passed in like the other
+    // per-account views.
+    pub fn get_file_shares(&self, _account_id: String, _file_id: String) -> Vec<(String, u8)> {
+        match self.shared_by_user.get(&_account_id) {
+            Some(share_ids) => share_ids
+                .iter()
+                .filter_map(|share_id| {
+                    let share_doc = self.shared_docs.get(&share_id)?;
+                    if share_doc.doc_id != _file_id || share_doc.is_expired() {
+                        return None;
+                    }
+                    let shared_with = share_recipient(&share_id, &share_doc, &_account_id)?;
+                    Some((shared_with, u8::from(share_doc.permission)))
+                })
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    // Shares of the doc, expired ones included. Shares still stored under a
+    // legacy id aren't counted until they are shared again.
+    pub fn get_share_count(&self, _doc_id: String) -> u64 {
+        self.share_counts.get(&_doc_id).unwrap_or(0)
+    }
+
+    // Outgoing shares of `account_id` with an expiry before `before`, including
+    // ones that already lapsed, so the owner can renew them.
+    pub fn get_expiring_shares(
+        &self,
+        account_id: String,
+        before: u64,
+        limit: u64,
+    ) -> Vec<(String, ShareDoc)> {
+        match self.shared_by_user.get(&account_id) {
+            Some(share_ids) => share_ids
+                .iter()
+                .filter_map(|share_id| {
+                    let share_doc = self.shared_docs.get(&share_id)?;
+                    match share_doc.expires_at {
+                        Some(expires_at) if expires_at < before => {
+                            Some((share_id, redact_share(share_doc)))
+                        }
+                        _ => None,
+                    }
+                })
+                .take(limit as usize)
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    // The share password is only a second gate on top of the share itself; the
+    // document stays protected by its own encryption.
+    pub fn open_shared_doc(
+        &self,
+        _doc_id: String,
+        _account_id: String,
+        _password: String,
+    ) -> Option<(ShareDoc, Option<File>, Option<FolderV2>)> {
+        let share_ids = self.shared_doc_of_user.get(&_account_id)?;
+        let share_doc = share_ids
+            .iter()
+            .filter_map(|share_id| self.shared_docs.get(&share_id))
+            .find(|share_doc| share_doc.doc_id == _doc_id && !share_doc.is_expired())?;
+        if !share_password_matches(&share_doc.share_password, &_password) {
+            return None;
+        }
+        let file = self.files.get(&_doc_id);
+        let folder = self.folders_v2.get(&_doc_id).map(redact_folder);
+        Some((redact_share(share_doc), file, folder))
+    }
+
+    // A share id names one share of a document with one account, and is what
+    // `shared_docs` is keyed by; the document the share points at is its
+    // `doc_id`. Use `compute_share_id` to get it from the owner, recipient and
+    // document id.
+    pub fn get_share(&self, share_id: String) -> Option<ShareDoc> {
+        self.shared_docs.get(&share_id).map(redact_share)
+    }
+
+    // Deprecated: despite its name `_doc_id` is a share id, as for `get_share`.
+    // Kept with its argument name so existing clients don't break.
+    pub fn get_shared_doc_detail(&self, _doc_id:String) -> (Option<ShareDoc>, Option<FolderV2>, Option<File>, String, bool) {
+        match self.shared_docs.get(&_doc_id) {
+            Some(doc) => {
+                let file = self.files.get(&doc.doc_id);
+                let folder = self.folders_v2.get(&doc.doc_id).map(redact_folder);
+                let expired = doc.is_expired();
+                (Some(redact_share(doc)), folder, file, _doc_id, expired)
+            },
+            None => (None,None,None,_doc_id,false)
+        }
+    }
+
+    pub fn get_shared_docs_detailed(
+        &self,
+        _account_id: String,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<SharedDocDetail> {
+        let shared_docs = match self.shared_doc_of_user.get(&_account_id) {
+            Some(shared_docs) => shared_docs,
+            None => return vec![],
+        };
+        let share_ids = shared_docs.as_vector();
+        let to_index = std::cmp::min(from_index.saturating_add(limit), share_ids.len());
+        (from_index..to_index)
+            .filter_map(|index| share_ids.get(index))
+            .filter_map(|share_id| {
+                let doc = self.shared_docs.get(&share_id)?;
+                let file = self.files.get(&doc.doc_id);
+                let folder = self.folders_v2.get(&doc.doc_id).map(redact_folder);
+                if file.is_none() && folder.is_none() {
+                    return None;
+                }
+                Some((share_id, Some(redact_share(doc)), file, folder))
+            })
+            .collect()
+    }
+
+    pub fn get_file_versions(&self, file_id: String) -> Vec<FileVersion> {
+        match self.files.get(&file_id) {
+            Some(file) => file.versions,
+            None => vec![],
+        }
+    }
+
+    pub fn get_file_info(&self, file_id: String) -> Option<File> {
+        self.files.get(&file_id)
+    }
+
+    // Only known for files added to a folder since the index was introduced.
+    pub fn get_file_parent(&self, file_id: String) -> Option<String> {
+        self.file_parent.get(&file_id)
+    }
+
+    pub fn get_files(&self, file_ids: Vec<String>) -> Vec<(String, Option<File>)> {
+        assert!(
+            file_ids.len() <= MAX_BATCH_VIEW,
+            "Can't fetch more than {} files in one call",
+            MAX_BATCH_VIEW
+        );
+        file_ids
+            .into_iter()
+            .map(|file_id| {
+                let file = self.files.get(&file_id);
+                (file_id, file)
+            })
+            .collect()
+    }
+
+    pub fn get_folders(&self, folder_ids: Vec<String>) -> Vec<(String, Option<FolderV2>)> {
+        assert!(
+            folder_ids.len() <= MAX_BATCH_VIEW,
+            "Can't fetch more than {} folders in one call",
+            MAX_BATCH_VIEW
+        );
+        folder_ids
+            .into_iter()
+            .map(|folder_id| {
+                let folder = self.folders_v2.get(&folder_id).map(redact_folder);
+                (folder_id, folder)
+            })
+            .collect()
+    }
+
+    pub fn get_folder_info_v2(&self, folder_id: String) -> Option<FolderV2> {
+        self.folders_v2.get(&folder_id).map(redact_folder)
+    }
+
+    // Compares digests so the stored password never leaves the contract.
+    pub fn verify_folder_password(&self, folder_id: String, candidate: String) -> bool {
+        match self
+            .folders_v2
+            .get(&folder_id)
+            .and_then(|folder| folder.folder_password)
+        {
+            Some(password) => env::sha256(password.as_bytes()) == env::sha256(candidate.as_bytes()),
+            None => false,
+        }
+    }
+
+    // Oldest first; only the last MAX_ACTIVITY_ENTRIES entries are kept.
+    pub fn get_activity(
+        &self,
+        account_id: String,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<ActivityEntry> {
+        let log = match self.activity.get(&account_id) {
+            Some(log) => log,
+            None => return vec![],
+        };
+        let len = log.entries.len();
+        let oldest = if log.written > len {
+            log.written % len
+        } else {
+            0
+        };
+        let to_index = std::cmp::min(from_index.saturating_add(limit), len);
+        (from_index..to_index)
+            .filter_map(|index| log.entries.get((oldest + index) % len))
+            .collect()
+    }
+
+    pub fn get_account_stats(&self, account_id: String) -> AccountStats {
+        self.stats.get(&account_id).unwrap_or_default()
+    }
+
+    // Everything the first screen needs in one call. At most MAX_BATCH_VIEW
+    // children and files are resolved; `get_children` pages through the rest.
+    pub fn get_home(&self, account_id: String) -> HomeView {
+        let root = self.folders_v2.get(&self.root_id_of(&account_id));
+        let (children, files) = match &root {
+            Some(root) => (
+                root.children
+                    .iter()
+                    .take(MAX_BATCH_VIEW)
+                    .filter_map(|child_id| {
+                        let summary = self.get_folder_summary(child_id.clone())?;
+                        Some((child_id.clone(), summary))
+                    })
+                    .collect(),
+                root.files
+                    .iter()
+                    .take(MAX_BATCH_VIEW)
+                    .filter_map(|file_id| {
+                        let file = self.files.get(file_id)?;
+                        Some((file_id.clone(), file))
+                    })
+                    .collect(),
+            ),
+            None => (vec![], vec![]),
+        };
+        HomeView {
+            user: self.users.get(&account_id).map(PublicUser::from),
+            root: root.map(redact_folder),
+            children,
+            files,
+        }
+    }
+
+    pub fn get_folder_summary(&self, folder_id: String) -> Option<FolderSummary> {
+        self.folders_v2.get(&folder_id).map(|folder| FolderSummary {
+            name: folder.name,
+            parent: folder.parent,
+            file_count: folder.files.len() as u64,
+            child_count: folder.children.len() as u64,
+            folder_type: folder.folder_type,
+            created_at: folder.created_at,
+        })
+    }
+
+    // Returns None instead of panicking when `account_id` can't read the folder.
+    pub fn get_shared_folder_contents(
+        &self,
+        folder_id: String,
+        account_id: String,
+    ) -> Option<(FolderV2, Vec<File>)> {
+        let (root_folder, root_folder_id) = self.find_root(folder_id.clone());
+        self.root_permission(&root_folder, &root_folder_id, &account_id)
+            .ok()?;
+        let folder = self.folders_v2.get(&folder_id)?;
+        let files = folder
+            .files
+            .iter()
+            .take(MAX_VIEW_NODES)
+            .filter_map(|file_id| self.files.get(file_id))
+            .collect();
+        Some((redact_folder(folder), files))
+    }
+
+    pub fn get_files_in_folder(
+        &self,
+        folder_id: String,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<(String, File)> {
+        match self.folders_v2.get(&folder_id) {
+            Some(folder) => folder
+                .files
+                .into_iter()
+                .skip(from_index as usize)
+                .take(limit as usize)
+                .filter_map(|file_id| self.files.get(&file_id).map(|file| (file_id, file)))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    // At most MAX_BATCH_VIEW children per page, whatever `limit` asks for.
+    pub fn get_children(
+        &self,
+        folder_id: String,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<(String, FolderV2)> {
+        let limit = std::cmp::min(limit, MAX_BATCH_VIEW as u64);
+        match self.folders_v2.get(&folder_id) {
+            Some(folder) => folder
+                .children
+                .into_iter()
+                .skip(from_index as usize)
+                .take(limit as usize)
+                .filter_map(|child_id| {
+                    self.folders_v2
+                        .get(&child_id)
+                        .map(|child| (child_id, redact_folder(child)))
+                })
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn list_shared_folders(&self, account_id: String) -> Vec<(String, FolderV2)> {
+        match self.folders_v2.get(&self.root_id_of(&account_id)) {
+            Some(root) => root
+                .children
+                .into_iter()
+                .take(MAX_VIEW_NODES)
+                .filter_map(|child_id| {
+                    self.folders_v2
+                        .get(&child_id)
+                        .map(|child| (child_id, redact_folder(child)))
+                })
+                .filter(|(_, child)| child.folder_type == Some(FolderKind::Shared))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn get_folder_size(&self, folder_id: String) -> u64 {
+        let (folders, _) = self.walk_subtree(folder_id, MAX_VIEW_NODES);
+        let mut budget = MAX_VIEW_NODES - folders.len();
+        let mut size: u64 = 0;
+        for (_, folder) in folders.iter() {
+            for file_id in folder.files.iter() {
+                if budget == 0 {
+                    return size;
+                }
+                budget -= 1;
+                if let Some(file) = self.files.get(file_id) {
+                    size += file.size;
+                }
+            }
+        }
+        size
+    }
+
+    // Bytes of every file the account created, in its own drive or in folders
+    // shared with it; the same total the quota is checked against.
+    pub fn get_account_storage(&self, account_id: String) -> u64 {
+        self.usage.get(&account_id).unwrap_or(0)
+    }
+
+    pub fn get_subtree(
+        &self,
+        folder_id: String,
+        max_nodes: u32,
+    ) -> (Vec<(String, FolderV2)>, bool) {
+        let max_nodes = std::cmp::min(max_nodes as usize, MAX_VIEW_NODES);
+        let (folders, truncated) = self.walk_subtree(folder_id, max_nodes);
+        let folders = folders
+            .into_iter()
+            .map(|(folder_id, folder)| (folder_id, redact_folder(folder)))
+            .collect();
+        (folders, truncated)
+    }
+
+    // Best-effort: only the first MAX_VIEW_NODES folders of the account are
+    // scanned, so matches in deeper folders can be missed.
+    pub fn search(
+        &self,
+        account_id: String,
+        query: String,
+        limit: u64,
+    ) -> Vec<(String, String, u8)> {
+        let query = query.to_lowercase();
+        let limit = limit as usize;
+        let mut result = Vec::new();
+        let root_id = self.root_id_of(&account_id);
+        let (folders, _) = self.walk_subtree(root_id.clone(), MAX_VIEW_NODES);
+        for (folder_id, folder) in folders {
+            if result.len() >= limit {
+                break;
+            }
+            if folder_id != root_id && folder.name.to_lowercase().contains(&query) {
+                result.push((folder_id, folder.name, u8::from(DocType::Folder)));
+            }
+            for file_id in folder.files {
+                if result.len() >= limit {
+                    break;
+                }
+                if let Some(file) = self.files.get(&file_id) {
+                    if file.name.to_lowercase().contains(&query) {
+                        result.push((file_id, file.name, u8::from(DocType::File)));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    // Scans the same bounded subtree as `search`.
+    pub fn find_by_tag(&self, account_id: String, tag: String, limit: u64) -> Vec<String> {
+        let tag = tag.trim().to_lowercase();
+        let limit = limit as usize;
+        let mut result = Vec::new();
+        let (folders, _) = self.walk_subtree(self.root_id_of(&account_id), MAX_VIEW_NODES);
+        for (folder_id, folder) in folders {
+            if result.len() >= limit {
+                break;
+            }
+            if folder.tags.contains(&tag) {
+                result.push(folder_id);
+            }
+            for file_id in folder.files {
+                if result.len() >= limit {
+                    break;
+                }
+                if let Some(file) = self.files.get(&file_id) {
+                    if file.tags.contains(&tag) {
+                        result.push(file_id);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    // Most recently touched files first, from the same bounded subtree as `search`.
+    pub fn get_recent_files(&self, account_id: String, limit: u64) -> Vec<(String, File)> {
+        let (folders, _) = self.walk_subtree(self.root_id_of(&account_id), MAX_VIEW_NODES);
+        let mut result: Vec<(String, File)> = folders
+            .into_iter()
+            .flat_map(|(_, folder)| folder.files)
+            .filter_map(|file_id| {
+                let file = self.files.get(&file_id)?;
+                file.last_accessed?;
+                Some((file_id, file))
+            })
+            .collect();
+        result.sort_by_key(|(_, file)| std::cmp::Reverse(file.last_accessed));
+        result.truncate(limit as usize);
+        result
+    }
+
+    // For incremental sync; `since` is a block timestamp in nanoseconds, as
+    // `last_update` is. Like `search`, only the first MAX_VIEW_NODES
+    // folders are scanned, so large accounts should sync per folder with
+    // `get_children` instead of relying on this alone.
+    pub fn get_files_modified_since(
+        &self,
+        account_id: String,
+        since: u64,
+        limit: u64,
+    ) -> Vec<(String, File)> {
+        let limit = limit as usize;
+        let mut result = Vec::new();
+        let (folders, _) = self.walk_subtree(self.root_id_of(&account_id), MAX_VIEW_NODES);
+        for (_, folder) in folders {
+            for file_id in folder.files {
+                if result.len() >= limit {
+                    return result;
+                }
+                if let Some(file) = self.files.get(&file_id) {
+                    if file.last_update >= since {
+                        result.push((file_id, file));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    pub fn get_path(&self, folder_id: String) -> Vec<(String, String)> {
+        let mut path = Vec::new();
+        let mut current_id = folder_id;
+        while path.len() < MAX_ANCESTOR_HOPS {
+            match self.folders_v2.get(&current_id) {
+                Some(folder) => {
+                    let parent_id = folder.parent;
+                    path.push((current_id.clone(), folder.name));
+                    if parent_id.eq(&current_id) {
+                        break;
+                    }
+                    current_id = parent_id;
+                }
+                None => break,
+            }
+        }
+        path.reverse();
+        path
+    }
+
+    // Same notion of root as `get_root`: a folder directly below an account's
+    // own folder. Costs two reads instead of a walk to the top.
+    pub fn is_root(&self, folder_id: String) -> bool {
+        match self.folders_v2.get(&folder_id) {
+            Some(folder) if folder.parent != folder_id => match self.folders_v2.get(&folder.parent)
+            {
+                Some(parent) => parent.parent == folder.parent,
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    pub fn owner_of_root(&self, folder_id: String) -> Option<String> {
+        self.root_owner(&self.find_root(folder_id).0)
+    }
+
+    pub fn get_root(&self, folder_id: String) -> (Option<FolderV2>, String) {
+        let (root_folder, root_folder_id) = self.find_root(folder_id);
+        (root_folder.map(redact_folder), root_folder_id)
+    }
+
+    // A folder in the trash, or below one, has no root, so nothing can be
+    // written to it until it is restored.
+    fn find_root(&self, folder_id: String) -> (Option<FolderV2>, String) {
+        let mut result = String::from("");
+        if let Some(folder_by_id) = self.folders_v2.get(&folder_id) {
+            let mut current_id = String::from(&folder_id[..]);
+            let mut parent_id = String::from(&folder_by_id.parent[..]);
+            let mut hops: usize = 0;
+            while current_id.ne(&parent_id[..]) {
+                if hops >= MAX_ANCESTOR_HOPS {
+                    env::log(
+                        format!("Ancestors of '{}' form a cycle, giving up", folder_id).as_bytes(),
+                    );
+                    return (None, String::new());
+                }
+                hops += 1;
+                match self.folders_v2.get(&parent_id) {
+                    Some(folder) => {
+                        // Trashing detaches a folder from its parent, so only
+                        // detached folders need the extra lookup.
+                        if !folder.children.contains(&current_id)
+                            && self.trashed.get(&current_id).is_some()
+                        {
+                            return (None, String::new());
+                        }
+                        let temp = current_id.clone();
+                        current_id = String::from(&parent_id[..]);
+                        parent_id = folder.parent;
+                        if current_id.eq(&parent_id) {
+                            result = String::from(&temp[..]);
+                        }
+                    }
+                    None => break,
+                };
             }
-            None => {}
         }
         match self.folders_v2.get(&result) {
             Some(root) => (Some(root), result),
@@ -519,3 +4614,1593 @@ impl Contract {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::json_types::ValidAccountId;
+    use near_sdk::serde_json;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    const DEPOSIT: Balance = 10_000_000_000_000_000_000_000_000;
+
+    // Every call in a test is made by `account` with enough deposit attached;
+    // the storage written by earlier calls is kept.
+    fn as_account(account: &str) {
+        as_account_with(account, DEPOSIT);
+    }
+
+    fn as_account_with(account: &str, deposit: Balance) {
+        let account = account.to_string();
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(ValidAccountId::try_from("contract").unwrap())
+            .signer_account_id(ValidAccountId::try_from(account.clone()).unwrap())
+            .predecessor_account_id(ValidAccountId::try_from(account).unwrap())
+            .attached_deposit(deposit)
+            .build());
+    }
+
+    fn setup(accounts: &[&str]) -> Contract {
+        as_account("owner");
+        let mut contract = Contract::new("owner".to_string());
+        for account in accounts {
+            as_account(account);
+            contract.sign_up(
+                format!("{}-key", account),
+                format!("{}-token", account),
+                1,
+                None,
+            );
+        }
+        contract
+    }
+
+    fn create_folder(contract: &mut Contract, id: &str, parent: &str, kind: Option<FolderKind>) {
+        let password = match kind {
+            Some(FolderKind::Shared) => Some(format!("{}-password", id)),
+            _ => None,
+        };
+        contract.create_folder_v2(
+            id.to_string(),
+            id.to_string(),
+            parent.to_string(),
+            password,
+            kind,
+            1,
+            None,
+            None,
+        );
+    }
+
+    fn create_file(contract: &mut Contract, folder: &str, id: &str) {
+        contract.create_file_v2(
+            folder.to_string(),
+            id.to_string(),
+            format!("{}-cid", id),
+            id.to_string(),
+            None,
+            "text/plain".to_string(),
+            10,
+            1,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    fn share_folder(contract: &mut Contract, folder: &str, with: &str, permission: Permission) {
+        contract.share_folder_v2(
+            folder.to_string(),
+            with.to_string(),
+            "share-password".to_string(),
+            permission,
+            1,
+            None,
+            None,
+        );
+    }
+
+    // alice owns the shared folder "team"; bob may write to it and carol may
+    // only read it.
+    fn setup_team() -> Contract {
+        let mut contract = setup(&["alice", "bob", "carol"]);
+        as_account("alice");
+        create_folder(&mut contract, "team", "alice", Some(FolderKind::Shared));
+        share_folder(&mut contract, "team", "bob", Permission::Write);
+        share_folder(&mut contract, "team", "carol", Permission::Read);
+        contract
+    }
+
+    #[test]
+    fn write_collaborator_can_rename() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "notes");
+        as_account("bob");
+        contract.rename_file(
+            "team".to_string(),
+            "notes".to_string(),
+            "minutes".to_string(),
+        );
+        contract.rename_folder("team".to_string(), "Team".to_string());
+        assert_eq!(
+            contract.get_file_info("notes".to_string()).unwrap().name,
+            "minutes"
+        );
+        assert_eq!(
+            contract
+                .get_folder_info_v2("team".to_string())
+                .unwrap()
+                .name,
+            "Team"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "You don't have permission to change this folder")]
+    fn read_collaborator_cannot_rename_file() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "notes");
+        as_account("carol");
+        contract.rename_file(
+            "team".to_string(),
+            "notes".to_string(),
+            "minutes".to_string(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "You don't have permission to change this folder")]
+    fn read_collaborator_cannot_rename_folder() {
+        let mut contract = setup_team();
+        as_account("carol");
+        contract.rename_folder("team".to_string(), "Team".to_string());
+    }
+
+    #[test]
+    fn owner_can_write_collaborator_files() {
+        let mut contract = setup_team();
+        as_account("bob");
+        create_file(&mut contract, "team", "draft");
+        as_account("alice");
+        contract.set_file_description("draft".to_string(), Some("reviewed".to_string()));
+        contract.add_tag("draft".to_string(), "final".to_string());
+        assert_eq!(
+            contract.effective_permission("draft".to_string(), "alice".to_string()),
+            2
+        );
+        assert_eq!(
+            contract.effective_permission("draft".to_string(), "carol".to_string()),
+            1
+        );
+        assert_eq!(
+            contract.effective_permission("draft".to_string(), "dave".to_string()),
+            0
+        );
+    }
+
+    #[test]
+    fn file_share_resolves_under_the_root_owner() {
+        let mut contract = setup_team();
+        as_account("bob");
+        create_file(&mut contract, "team", "draft");
+        as_account("alice");
+        create_folder(&mut contract, "docs", "alice", Some(FolderKind::Common));
+        contract.move_file("draft".to_string(), "team".to_string(), "docs".to_string());
+        contract.share_file_v2(
+            "draft".to_string(),
+            "dave".to_string(),
+            "docs".to_string(),
+            "share-password".to_string(),
+            Permission::Read,
+            1,
+            None,
+            None,
+        );
+        assert_eq!(
+            contract.effective_permission("draft".to_string(), "dave".to_string()),
+            1
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "You don't have write access to draft")]
+    fn read_collaborator_cannot_tag() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "draft");
+        as_account("carol");
+        contract.add_tag("draft".to_string(), "final".to_string());
+    }
+
+    #[test]
+    fn share_count_follows_share_and_revoke() {
+        let mut contract = setup_team();
+        assert_eq!(contract.get_share_count("team".to_string()), 2);
+        share_folder(&mut contract, "team", "bob", Permission::Read);
+        assert_eq!(contract.get_share_count("team".to_string()), 2);
+        contract.revoke_share("team".to_string(), "bob".to_string());
+        assert_eq!(contract.get_share_count("team".to_string()), 1);
+        as_account("carol");
+        contract.leave_share("team".to_string(), "alice".to_string());
+        assert_eq!(contract.get_share_count("team".to_string()), 0);
+    }
+
+    #[test]
+    fn legacy_shares_are_not_counted() {
+        let mut contract = setup_team();
+        let legacy_id = legacy_share_doc_id("alice", "dave", "team");
+        let legacy = ShareDoc {
+            doc_id: "team".to_string(),
+            share_password: String::new(),
+            permission: Permission::Read,
+            created_at: 1,
+            doc_type: DocType::Folder,
+            expires_at: None,
+            shared_with: "dave".to_string(),
+            shared_by: String::new(),
+        };
+        contract.shared_docs.insert(&legacy_id, &legacy);
+        insert_into_set(
+            &mut contract.shared_by_user,
+            b"sdbu",
+            &"alice".to_string(),
+            &legacy_id,
+        );
+        insert_into_set(
+            &mut contract.shared_doc_of_user,
+            b"sdou",
+            &"dave".to_string(),
+            &legacy_id,
+        );
+
+        contract.revoke_share("team".to_string(), "dave".to_string());
+        assert_eq!(contract.get_share_count("team".to_string()), 2);
+        assert!(contract
+            .get_shared_doc_of_user("dave".to_string())
+            .is_empty());
+    }
+
+    fn share_file(contract: &mut Contract, file: &str, parent: &str, with: &str) {
+        contract.share_file_v2(
+            file.to_string(),
+            with.to_string(),
+            parent.to_string(),
+            "share-password".to_string(),
+            Permission::Read,
+            1,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn removing_docs_drops_their_shares() {
+        let mut contract = setup(&["alice", "bob"]);
+        as_account("alice");
+        create_folder(&mut contract, "docs", "alice", Some(FolderKind::Common));
+        create_folder(&mut contract, "sub", "docs", Some(FolderKind::Common));
+        create_file(&mut contract, "docs", "top");
+        create_file(&mut contract, "sub", "nested");
+        share_file(&mut contract, "top", "docs", "bob");
+        share_file(&mut contract, "nested", "docs", "bob");
+        assert_eq!(contract.get_shared_doc_of_user("bob".to_string()).len(), 2);
+
+        contract.remove_folder_v2("sub".to_string());
+        let shared = contract.get_shared_doc_of_user("bob".to_string());
+        assert_eq!(
+            shared,
+            vec![contract.compute_share_id(
+                "alice".to_string(),
+                "bob".to_string(),
+                "top".to_string()
+            )]
+        );
+        contract.remove_file_v2("docs".to_string(), "top".to_string());
+        assert!(contract
+            .get_shared_doc_of_user("bob".to_string())
+            .is_empty());
+        assert!(contract.get_shared_by_me("alice".to_string()).is_empty());
+        assert_eq!(contract.get_share_count("nested".to_string()), 0);
+    }
+
+    #[test]
+    fn manager_changes_own_shares() {
+        let mut contract = setup_team();
+        contract.update_permission("team".to_string(), "bob".to_string(), Permission::Manage);
+        as_account("bob");
+        share_folder(&mut contract, "team", "dave", Permission::Read);
+        contract.update_permission("team".to_string(), "dave".to_string(), Permission::Write);
+        contract.extend_share("team".to_string(), "dave".to_string(), Some(u64::MAX));
+        assert_eq!(
+            contract.effective_permission("team".to_string(), "dave".to_string()),
+            2
+        );
+        contract.revoke_share("team".to_string(), "dave".to_string());
+        assert_eq!(
+            contract.effective_permission("team".to_string(), "dave".to_string()),
+            0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Only an owner can change a share an owner created")]
+    fn manager_cannot_overwrite_owner_share() {
+        let mut contract = setup_team();
+        contract.update_permission("team".to_string(), "bob".to_string(), Permission::Manage);
+        as_account("bob");
+        share_folder(&mut contract, "team", "carol", Permission::Write);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only an owner can change a share an owner created")]
+    fn manager_cannot_revoke_owner_share() {
+        let mut contract = setup_team();
+        contract.update_permission("team".to_string(), "bob".to_string(), Permission::Manage);
+        as_account("bob");
+        contract.revoke_share("team".to_string(), "carol".to_string());
+    }
+
+    #[test]
+    fn co_owner_manages_every_share() {
+        let mut contract = setup_team();
+        contract.add_owner("team".to_string(), "dave".to_string());
+        as_account("dave");
+        contract.update_permission("team".to_string(), "carol".to_string(), Permission::Write);
+        contract.revoke_share("team".to_string(), "bob".to_string());
+        assert_eq!(
+            contract.effective_permission("team".to_string(), "carol".to_string()),
+            2
+        );
+        assert_eq!(
+            contract.effective_permission("team".to_string(), "bob".to_string()),
+            0
+        );
+        assert_eq!(contract.get_shared_by_me("alice".to_string()).len(), 1);
+    }
+
+    #[test]
+    fn delete_account_drops_received_shares() {
+        let mut contract = setup_team();
+        as_account("bob");
+        while !contract.delete_account() {}
+        assert!(contract
+            .get_shared_doc_of_user("bob".to_string())
+            .is_empty());
+        assert_eq!(contract.get_shared_by_me("alice".to_string()).len(), 1);
+        assert_eq!(contract.get_share_count("team".to_string()), 1);
+        let share_id =
+            contract.compute_share_id("alice".to_string(), "bob".to_string(), "team".to_string());
+        assert!(contract.get_share(share_id).is_none());
+    }
+
+    #[test]
+    fn transfer_ownership_keeps_shares() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "readme");
+        let mut cursor = contract.transfer_ownership("erin".to_string(), None);
+        while cursor.is_some() {
+            cursor = contract.transfer_ownership("erin".to_string(), cursor);
+        }
+
+        assert_eq!(
+            contract.owner_of_root("team".to_string()),
+            Some("erin".to_string())
+        );
+        assert_eq!(
+            contract.effective_permission("team".to_string(), "bob".to_string()),
+            2
+        );
+        assert_eq!(
+            contract.effective_permission("team".to_string(), "carol".to_string()),
+            1
+        );
+        assert!(contract.get_shared_by_me("alice".to_string()).is_empty());
+        assert_eq!(contract.get_shared_by_me("erin".to_string()).len(), 2);
+        assert_eq!(contract.get_share_count("team".to_string()), 2);
+        assert_eq!(
+            contract
+                .get_file_info("readme".to_string())
+                .unwrap()
+                .created_by,
+            "erin"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "No transfer to alice in progress")]
+    fn transfer_cursor_needs_a_started_transfer() {
+        let mut contract = setup(&["alice", "mallory"]);
+        as_account("mallory");
+        create_folder(&mut contract, "junk", "mallory", None);
+        create_file(&mut contract, "junk", "big");
+        as_account("mallory");
+        contract.transfer_ownership("alice".to_string(), Some(vec!["junk".to_string()]));
+    }
+
+    #[test]
+    #[should_panic(expected = "No transfer to dave in progress")]
+    fn transfer_cursor_must_match_the_new_owner() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "readme");
+        let cursor = contract.transfer_ownership("erin".to_string(), None);
+        contract.transfer_ownership("dave".to_string(), cursor.or_else(|| Some(vec![])));
+    }
+
+    #[test]
+    fn gift_moves_only_once_accepted() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "readme");
+        contract.gift_folder("team".to_string(), "carol".to_string());
+        assert_eq!(
+            contract.owner_of_root("team".to_string()),
+            Some("alice".to_string())
+        );
+
+        as_account("carol");
+        let mut cursor = contract.accept_gift("team".to_string(), None);
+        while cursor.is_some() {
+            cursor = contract.accept_gift("team".to_string(), cursor);
+        }
+        assert_eq!(
+            contract.owner_of_root("team".to_string()),
+            Some("carol".to_string())
+        );
+        assert_eq!(
+            contract.effective_permission("team".to_string(), "bob".to_string()),
+            0
+        );
+        assert_eq!(contract.get_usage("carol".to_string()).0, 10);
+        assert_eq!(contract.get_usage("alice".to_string()).0, 0);
+        assert!(contract.get_gift_offer("team".to_string()).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Storage quota exceeded")]
+    fn accept_gift_checks_quota() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "readme");
+        contract.gift_folder("team".to_string(), "carol".to_string());
+        as_account("owner");
+        contract.set_quota("carol".to_string(), 5);
+        as_account("carol");
+        contract.accept_gift("team".to_string(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "No gift offered for 'team'")]
+    fn declined_gift_cannot_be_accepted() {
+        let mut contract = setup_team();
+        contract.gift_folder("team".to_string(), "carol".to_string());
+        as_account("carol");
+        contract.cancel_gift("team".to_string());
+        contract.accept_gift("team".to_string(), None);
+    }
+
+    #[test]
+    fn purges_only_unreferenced_files() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "kept");
+        create_file(&mut contract, "team", "legacy");
+        create_file(&mut contract, "team", "lost");
+        contract.file_parent.remove(&"legacy".to_string());
+        let mut team = contract.folders_v2.get(&"team".to_string()).unwrap();
+        team.files.retain(|file_id| file_id != "lost");
+        contract.folders_v2.insert(&"team".to_string(), &team);
+
+        let (orphans, next_index) = contract.find_orphaned_files("alice".to_string(), 0, 100);
+        assert_eq!(orphans, vec!["lost".to_string()]);
+        assert_eq!(next_index, None);
+        let (_, next_index) = contract.find_orphaned_files("alice".to_string(), 0, 1);
+        assert_eq!(next_index, Some(1));
+
+        as_account("owner");
+        let purged = contract.purge_orphaned_files(vec![
+            "kept".to_string(),
+            "legacy".to_string(),
+            "lost".to_string(),
+        ]);
+        assert_eq!(purged, vec!["lost".to_string()]);
+        assert!(contract.get_file_info("lost".to_string()).is_none());
+        assert!(contract.get_file_info("legacy".to_string()).is_some());
+    }
+
+    #[test]
+    fn estimate_covers_first_file() {
+        let mut contract = setup(&["alice"]);
+        as_account("alice");
+        create_folder(&mut contract, "docs", "alice", None);
+        contract.activity.remove(&"alice".to_string());
+        contract.stats.remove(&"alice".to_string());
+        contract.storage_used.remove(&"alice".to_string());
+
+        let initial_storage = env::storage_usage();
+        contract.create_file_v2(
+            "docs".to_string(),
+            "report".to_string(),
+            "report-cid".to_string(),
+            "report".to_string(),
+            Some("report-password".to_string()),
+            "application/pdf".to_string(),
+            10,
+            1,
+            None,
+            Some("request-1".to_string()),
+            None,
+            None,
+        );
+        let used = Balance::from(env::storage_usage() - initial_storage);
+        let estimate = contract.estimate_file_cost(6, 10, true);
+        assert!(used * env::storage_byte_cost() <= estimate.0);
+    }
+
+    #[test]
+    fn activity_omits_link_tokens() {
+        let mut contract = setup_team();
+        let token = contract.create_public_link(
+            "team".to_string(),
+            "link-password".to_string(),
+            Permission::Read,
+            None,
+            None,
+        );
+        contract.revoke_public_link(token.clone());
+        let activity = contract.get_activity("alice".to_string(), 0, 100);
+        assert!(activity.iter().all(|entry| entry.target != token));
+    }
+
+    #[test]
+    #[should_panic(expected = "Must attach")]
+    fn sharing_pays_for_its_activity_entry() {
+        let mut contract = setup(&["alice", "bob"]);
+        as_account("alice");
+        create_folder(&mut contract, "team", "alice", Some(FolderKind::Shared));
+        as_account_with("alice", 0);
+        share_folder(&mut contract, "team", "bob", Permission::Read);
+    }
+
+    #[test]
+    fn revoking_needs_no_deposit() {
+        let mut contract = setup_team();
+        as_account_with("alice", 0);
+        contract.revoke_share("team".to_string(), "bob".to_string());
+        assert_eq!(
+            contract.effective_permission("team".to_string(), "bob".to_string()),
+            0
+        );
+    }
+
+    #[test]
+    fn write_collaborator_sets_file_password() {
+        let mut contract = setup_team();
+        create_folder(&mut contract, "drafts", "team", None);
+        create_file(&mut contract, "drafts", "notes");
+        as_account("bob");
+        contract.set_file_password("notes".to_string(), Some("sealed".to_string()));
+        let file = contract.get_file_info("notes".to_string()).unwrap();
+        assert_eq!(file.encrypted_password, Some("sealed".to_string()));
+        assert_eq!(file.update_by, "bob");
+    }
+
+    #[test]
+    #[should_panic(expected = "You don't have write access to notes")]
+    fn read_collaborator_cannot_set_file_password() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "notes");
+        as_account("carol");
+        contract.set_file_password("notes".to_string(), None);
+    }
+
+    #[test]
+    fn custom_root_id_works_like_an_account_root() {
+        let mut contract = setup(&["bob"]);
+        as_account("alice");
+        contract.sign_up(
+            "alice-key".to_string(),
+            "alice-token".to_string(),
+            1,
+            Some("alice-drive".to_string()),
+        );
+        assert_eq!(
+            contract.get_root_folder_id("alice".to_string()),
+            Some("alice-drive".to_string())
+        );
+        create_folder(
+            &mut contract,
+            "team",
+            "alice-drive",
+            Some(FolderKind::Shared),
+        );
+        create_file(&mut contract, "team", "notes");
+        share_folder(&mut contract, "team", "bob", Permission::Write);
+
+        assert_eq!(
+            contract.owner_of_root("team".to_string()),
+            Some("alice".to_string())
+        );
+        assert_eq!(
+            contract.effective_permission("alice-drive".to_string(), "alice".to_string()),
+            2
+        );
+        assert_eq!(
+            contract.effective_permission("notes".to_string(), "bob".to_string()),
+            2
+        );
+        assert_eq!(contract.list_shared_folders("alice".to_string()).len(), 1);
+    }
+
+    #[test]
+    fn migrate_root_moves_an_account_keyed_root() {
+        let mut contract = setup_team();
+        create_folder(&mut contract, "old", "alice", None);
+        contract.remove_folder_v2("old".to_string());
+        contract.migrate_root("alice-drive".to_string());
+
+        assert!(contract.folders_v2.get(&"alice".to_string()).is_none());
+        assert_eq!(
+            contract.get_root_folder_id("alice".to_string()),
+            Some("alice-drive".to_string())
+        );
+        assert_eq!(
+            contract
+                .get_folder_info_v2("team".to_string())
+                .unwrap()
+                .parent,
+            "alice-drive"
+        );
+        assert_eq!(
+            contract.effective_permission("team".to_string(), "bob".to_string()),
+            2
+        );
+        contract.restore_item("old".to_string());
+        assert_eq!(
+            contract
+                .get_folder_info_v2("old".to_string())
+                .unwrap()
+                .parent,
+            "alice-drive"
+        );
+
+        // The account id is free again, so no one can take it as a root.
+        as_account("erin");
+        contract.sign_up("erin-key".to_string(), "erin-token".to_string(), 1, None);
+        create_folder(&mut contract, "erin-docs", "erin", None);
+    }
+
+    #[test]
+    #[should_panic(expected = "folder_id can't eq user_id")]
+    fn root_id_cannot_be_another_account() {
+        let mut contract = setup(&["bob"]);
+        as_account("alice");
+        contract.sign_up(
+            "alice-key".to_string(),
+            "alice-token".to_string(),
+            1,
+            Some("bob".to_string()),
+        );
+    }
+
+    #[test]
+    fn custom_roots_survive_gifts_and_transfers() {
+        let mut contract = setup_team();
+        contract.migrate_root("alice-drive".to_string());
+        as_account("carol");
+        contract.migrate_root("carol-drive".to_string());
+        create_folder(&mut contract, "album", "carol-drive", None);
+        create_file(&mut contract, "album", "photo");
+        contract.gift_folder("album".to_string(), "alice".to_string());
+
+        as_account("alice");
+        let mut cursor = contract.accept_gift("album".to_string(), None);
+        while cursor.is_some() {
+            cursor = contract.accept_gift("album".to_string(), cursor);
+        }
+        assert_eq!(
+            contract
+                .get_folder_info_v2("album".to_string())
+                .unwrap()
+                .parent,
+            "alice-drive"
+        );
+
+        let mut cursor = contract.transfer_ownership("erin".to_string(), None);
+        while cursor.is_some() {
+            cursor = contract.transfer_ownership("erin".to_string(), cursor);
+        }
+        assert_eq!(
+            contract.get_root_folder_id("erin".to_string()),
+            Some("alice-drive".to_string())
+        );
+        assert_eq!(
+            contract.owner_of_root("album".to_string()),
+            Some("erin".to_string())
+        );
+        assert_eq!(
+            contract.effective_permission("team".to_string(), "bob".to_string()),
+            2
+        );
+        assert_eq!(
+            contract.get_account_stats("erin".to_string()).folder_count,
+            2
+        );
+    }
+
+    #[test]
+    fn replayed_requests_change_nothing() {
+        let mut contract = setup_team();
+        let files = || {
+            vec![FileInput {
+                file_id: "notes".to_string(),
+                cid: "notes-cid".to_string(),
+                name: "notes".to_string(),
+                encrypted_password: None,
+                file_type: "text/plain".to_string(),
+                size: 10,
+                mime: None,
+                thumbnail_cid: None,
+            }]
+        };
+        let request_id = Some("batch-1".to_string());
+        contract.create_files_v2("team".to_string(), files(), 1, request_id.clone());
+        let replayed = contract.create_files_v2("team".to_string(), files(), 1, request_id);
+        assert_eq!(replayed, vec![("notes".to_string(), false)]);
+
+        let request_id = Some("copy-1".to_string());
+        contract.copy_file(
+            "notes".to_string(),
+            "team".to_string(),
+            "notes-copy".to_string(),
+            1,
+            request_id.clone(),
+        );
+        contract.copy_file(
+            "notes".to_string(),
+            "team".to_string(),
+            "notes-copy".to_string(),
+            1,
+            request_id,
+        );
+        assert_eq!(contract.get_usage("alice".to_string()).0, 20);
+
+        let link = |contract: &mut Contract| {
+            contract.create_public_link(
+                "team".to_string(),
+                "link-password".to_string(),
+                Permission::Read,
+                None,
+                Some("link-1".to_string()),
+            )
+        };
+        assert!(!link(&mut contract).is_empty());
+        assert!(link(&mut contract).is_empty());
+    }
+
+    #[test]
+    fn shares_a_file_two_levels_below_the_root() {
+        let mut contract = setup(&["alice", "bob"]);
+        as_account("alice");
+        create_folder(&mut contract, "work", "alice", Some(FolderKind::Common));
+        create_folder(&mut contract, "reports", "work", None);
+        create_folder(&mut contract, "q1", "reports", None);
+        create_file(&mut contract, "q1", "summary");
+        contract.share_file_v2(
+            "summary".to_string(),
+            "bob".to_string(),
+            "work".to_string(),
+            "share-password".to_string(),
+            Permission::Read,
+            1,
+            None,
+            None,
+        );
+        assert_eq!(
+            contract.effective_permission("summary".to_string(), "bob".to_string()),
+            1
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "file summary not found in folder other")]
+    fn cannot_share_a_file_outside_the_folder() {
+        let mut contract = setup(&["alice", "bob"]);
+        as_account("alice");
+        create_folder(&mut contract, "work", "alice", Some(FolderKind::Common));
+        create_folder(&mut contract, "other", "alice", Some(FolderKind::Common));
+        create_file(&mut contract, "work", "summary");
+        contract.share_file_v2(
+            "summary".to_string(),
+            "bob".to_string(),
+            "other".to_string(),
+            "share-password".to_string(),
+            Permission::Read,
+            1,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn freed_storage_is_credited_back() {
+        let mut contract = setup_team();
+        let before = contract.storage_balance_of("alice".to_string()).0;
+        create_file(&mut contract, "team", "notes");
+        let with_file = contract.storage_balance_of("alice".to_string()).0;
+        assert!(with_file > before);
+        contract.remove_file_v2("team".to_string(), "notes".to_string());
+        contract.purge_item("notes".to_string());
+        assert!(contract.storage_balance_of("alice".to_string()).0 < with_file);
+    }
+
+    #[test]
+    fn account_storage_counts_files_everywhere() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "notes");
+        as_account("bob");
+        create_file(&mut contract, "team", "draft");
+        assert_eq!(contract.get_account_storage("alice".to_string()), 10);
+        assert_eq!(contract.get_account_storage("bob".to_string()), 10);
+        assert_eq!(contract.get_folder_size("team".to_string()), 20);
+    }
+
+    // alice's drive with max_depth 2: "a" and "a/b" at the limit, and "x/y"
+    // ready to be moved.
+    fn setup_depth_limit() -> Contract {
+        let mut contract = setup(&["alice"]);
+        as_account("owner");
+        contract.set_max_depth(2);
+        as_account("alice");
+        create_folder(&mut contract, "a", "alice", None);
+        create_folder(&mut contract, "b", "a", None);
+        create_folder(&mut contract, "x", "alice", None);
+        create_folder(&mut contract, "y", "x", None);
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = "Folders can't be nested more than 2 levels deep")]
+    fn create_respects_max_depth() {
+        let mut contract = setup_depth_limit();
+        create_folder(&mut contract, "c", "b", None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Folders can't be nested more than 2 levels deep")]
+    fn move_respects_max_depth() {
+        let mut contract = setup_depth_limit();
+        contract.move_folder("x".to_string(), "a".to_string());
+    }
+
+    #[test]
+    fn move_within_max_depth() {
+        let mut contract = setup_depth_limit();
+        contract.move_folder("y".to_string(), "a".to_string());
+        assert_eq!(
+            contract.get_folder_info_v2("y".to_string()).unwrap().parent,
+            "a"
+        );
+    }
+
+    #[test]
+    fn get_children_caps_the_page() {
+        let mut contract = setup(&["alice"]);
+        for index in 0..MAX_BATCH_VIEW + 1 {
+            as_account("alice");
+            create_folder(&mut contract, &format!("folder-{}", index), "alice", None);
+        }
+        let page = contract.get_children("alice".to_string(), 0, u64::MAX);
+        assert_eq!(page.len(), MAX_BATCH_VIEW);
+        let rest = contract.get_children("alice".to_string(), MAX_BATCH_VIEW as u64, 10);
+        assert_eq!(rest.len(), 1);
+    }
+
+    #[test]
+    fn public_views_omit_secrets() {
+        let mut contract = setup(&["alice"]);
+        contract.create_folder_v2(
+            "team".to_string(),
+            "Team".to_string(),
+            "alice".to_string(),
+            Some("folder-secret".to_string()),
+            Some(FolderKind::Shared),
+            1,
+            None,
+            None,
+        );
+
+        let user = serde_json::to_string(&contract.get_user("alice".to_string())).unwrap();
+        assert!(user.contains("alice-key"));
+        assert!(!user.contains("alice-token"));
+        let home = serde_json::to_string(&contract.get_home("alice".to_string())).unwrap();
+        assert!(!home.contains("alice-token"));
+        let folder =
+            serde_json::to_string(&contract.get_folder_info_v2("team".to_string())).unwrap();
+        assert!(!folder.contains("folder-secret"));
+
+        assert_eq!(
+            contract.get_own_user().unwrap().encrypted_token,
+            "alice-token"
+        );
+        assert!(contract.verify_folder_password("team".to_string(), "folder-secret".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner or a manager can share this doc")]
+    fn write_collaborator_cannot_share_a_folder() {
+        let mut contract = setup_team();
+        as_account("bob");
+        share_folder(&mut contract, "team", "dave", Permission::Read);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner or a manager can share this doc")]
+    fn write_collaborator_cannot_share_a_file() {
+        let mut contract = setup_team();
+        as_account("bob");
+        create_file(&mut contract, "team", "draft");
+        share_file(&mut contract, "draft", "team", "dave");
+    }
+
+    #[test]
+    fn permission_out_of_range_is_rejected() {
+        assert_eq!(
+            serde_json::from_str::<Permission>("2").unwrap(),
+            Permission::Write
+        );
+        for value in &["0", "5"] {
+            let error = serde_json::from_str::<Permission>(value).unwrap_err();
+            assert!(error
+                .to_string()
+                .contains(&format!("invalid Permission value {}", value)));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot share to your self")]
+    fn cannot_share_a_folder_with_yourself() {
+        let mut contract = setup_team();
+        share_folder(&mut contract, "team", "alice", Permission::Read);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't share to your self alice - alice")]
+    fn cannot_share_a_file_with_yourself() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "draft");
+        share_file(&mut contract, "draft", "team", "alice");
+    }
+
+    #[test]
+    fn write_collaborator_works_in_nested_folders() {
+        let mut contract = setup_team();
+        as_account("bob");
+        create_folder(&mut contract, "plans", "team", None);
+        create_folder(&mut contract, "q3", "plans", None);
+        create_file(&mut contract, "q3", "budget");
+        contract.rename_folder("q3".to_string(), "Q3".to_string());
+        contract.rename_file("q3".to_string(), "budget".to_string(), "Budget".to_string());
+
+        let file = contract.get_file_info("budget".to_string()).unwrap();
+        assert_eq!(file.name, "Budget");
+        assert_eq!(
+            contract.get_folder_info_v2("q3".to_string()).unwrap().name,
+            "Q3"
+        );
+        assert!(contract.can_write("q3".to_string(), "bob".to_string()));
+        assert_eq!(
+            contract.effective_permission("budget".to_string(), "carol".to_string()),
+            1
+        );
+    }
+
+    #[test]
+    fn owner_and_write_collaborator_can_remove() {
+        let mut contract = setup_team();
+        for account in &["alice", "bob"] {
+            as_account(account);
+            let folder = format!("{}-folder", account);
+            let file = format!("{}-file", account);
+            create_folder(&mut contract, &folder, "team", None);
+            create_file(&mut contract, "team", &file);
+            as_account(account);
+            contract.remove_file_v2("team".to_string(), file.clone());
+            as_account(account);
+            contract.remove_folder_v2(folder);
+        }
+        let team = contract.get_folder_info_v2("team".to_string()).unwrap();
+        assert!(team.files.is_empty());
+        assert!(contract.get_children("team".to_string(), 0, 10).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "You don't have permission to change this folder")]
+    fn read_collaborator_cannot_remove_file() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "notes");
+        as_account("carol");
+        contract.remove_file_v2("team".to_string(), "notes".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "You don't have permission to change this folder")]
+    fn read_collaborator_cannot_remove_folder() {
+        let mut contract = setup_team();
+        create_folder(&mut contract, "plans", "team", None);
+        as_account("carol");
+        contract.remove_folder_v2("plans".to_string());
+    }
+
+    #[test]
+    fn removing_a_file_twice_is_harmless() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "notes");
+        create_file(&mut contract, "team", "minutes");
+        as_account("alice");
+        contract.remove_file_v2("team".to_string(), "notes".to_string());
+        as_account("alice");
+        contract.remove_file_v2("team".to_string(), "notes".to_string());
+        let team = contract.get_folder_info_v2("team".to_string()).unwrap();
+        assert_eq!(team.files, vec!["minutes".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Contract is paused")]
+    fn create_is_refused_while_paused() {
+        let mut contract = setup(&["alice"]);
+        as_account("owner");
+        contract.set_paused(true);
+        as_account("alice");
+        create_folder(&mut contract, "docs", "alice", None);
+    }
+
+    #[test]
+    fn create_works_after_unpausing() {
+        let mut contract = setup(&["alice"]);
+        as_account("owner");
+        contract.set_paused(true);
+        assert!(contract.get_user("alice".to_string()).is_some());
+        contract.set_paused(false);
+        as_account("alice");
+        create_folder(&mut contract, "docs", "alice", None);
+        assert!(contract.get_folder_info_v2("docs".to_string()).is_some());
+    }
+
+    fn create_folder_with(
+        contract: &mut Contract,
+        parent: &str,
+        password: Option<&str>,
+        kind: Option<FolderKind>,
+    ) {
+        as_account("alice");
+        contract.create_folder_v2(
+            "docs".to_string(),
+            "docs".to_string(),
+            parent.to_string(),
+            password.map(|password| password.to_string()),
+            kind,
+            1,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Parent folder not found: 'nowhere'")]
+    fn create_folder_needs_a_parent() {
+        let mut contract = setup(&["alice"]);
+        create_folder_with(&mut contract, "nowhere", None, None);
+    }
+
+    #[test]
+    fn folder_type_out_of_range_is_rejected() {
+        let error = serde_json::from_str::<FolderKind>("3").unwrap_err();
+        assert!(error.to_string().contains("invalid FolderKind value 3"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Shared folders require a password")]
+    fn shared_folder_needs_a_password() {
+        let mut contract = setup(&["alice"]);
+        create_folder_with(&mut contract, "alice", None, Some(FolderKind::Shared));
+    }
+
+    #[test]
+    #[should_panic(expected = "Shared folders require a password")]
+    fn shared_folder_password_cannot_be_empty() {
+        let mut contract = setup(&["alice"]);
+        create_folder_with(&mut contract, "alice", Some(""), Some(FolderKind::Shared));
+    }
+
+    #[test]
+    #[should_panic(expected = "Common folders can't have a password")]
+    fn common_folder_cannot_have_a_password() {
+        let mut contract = setup(&["alice"]);
+        create_folder_with(
+            &mut contract,
+            "alice",
+            Some("secret"),
+            Some(FolderKind::Common),
+        );
+    }
+
+    fn set_parent(contract: &mut Contract, folder_id: &str, parent: &str) {
+        let mut folder = contract.folders_v2.get(&folder_id.to_string()).unwrap();
+        folder.parent = parent.to_string();
+        contract.folders_v2.insert(&folder_id.to_string(), &folder);
+    }
+
+    #[test]
+    fn get_root_gives_up_on_a_cycle() {
+        let mut contract = setup(&["alice"]);
+        as_account("alice");
+        create_folder(&mut contract, "a", "alice", None);
+        create_folder(&mut contract, "b", "a", None);
+        set_parent(&mut contract, "a", "b");
+        let (root, root_id) = contract.get_root("b".to_string());
+        assert!(root.is_none());
+        assert_eq!(root_id, "");
+    }
+
+    #[test]
+    fn get_root_stops_at_a_missing_parent() {
+        let mut contract = setup(&["alice"]);
+        as_account("alice");
+        create_folder(&mut contract, "a", "alice", None);
+        create_folder(&mut contract, "b", "a", None);
+        set_parent(&mut contract, "a", "gone");
+        let (root, root_id) = contract.get_root("b".to_string());
+        assert!(root.is_none());
+        assert_eq!(root_id, "");
+    }
+
+    fn stats_of(contract: &Contract, account_id: &str) -> (u64, u64) {
+        let stats = contract.get_account_stats(account_id.to_string());
+        (stats.file_count, stats.folder_count)
+    }
+
+    #[test]
+    fn stats_follow_creates_and_removes() {
+        let mut contract = setup_team();
+        assert_eq!(stats_of(&contract, "alice"), (0, 1));
+        as_account("alice");
+        create_folder(&mut contract, "plans", "team", None);
+        create_folder(&mut contract, "q3", "plans", None);
+        create_file(&mut contract, "plans", "roadmap");
+        create_file(&mut contract, "q3", "budget");
+        as_account("bob");
+        create_file(&mut contract, "team", "draft");
+        assert_eq!(stats_of(&contract, "alice"), (2, 3));
+        assert_eq!(stats_of(&contract, "bob"), (1, 0));
+
+        // Trashed items still count until they are purged.
+        as_account("alice");
+        contract.remove_folder_v2("plans".to_string());
+        assert_eq!(stats_of(&contract, "alice"), (2, 3));
+        as_account("alice");
+        assert!(contract.purge_item("plans".to_string()));
+        assert_eq!(stats_of(&contract, "alice"), (0, 1));
+
+        as_account("alice");
+        contract.remove_file_v2("team".to_string(), "draft".to_string());
+        as_account("alice");
+        contract.purge_item("draft".to_string());
+        assert_eq!(stats_of(&contract, "bob"), (0, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "You don't have permission to change this folder")]
+    fn read_collaborator_collision_reports_permission() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "notes");
+        as_account("carol");
+        create_file(&mut contract, "team", "notes");
+    }
+
+    #[test]
+    fn share_ids_keep_underscored_triples_apart() {
+        as_account("alice");
+        assert_eq!(
+            legacy_share_doc_id("a_b", "c", "d"),
+            legacy_share_doc_id("a", "b_c", "d")
+        );
+        assert_ne!(share_doc_id("a_b", "c", "d"), share_doc_id("a", "b_c", "d"));
+        assert_ne!(share_doc_id("a", "b_c", "d"), share_doc_id("a", "b", "c_d"));
+    }
+
+    #[test]
+    fn underscored_shares_are_stored_apart() {
+        let mut contract = setup(&["alice"]);
+        as_account("alice");
+        create_folder(&mut contract, "team", "alice", Some(FolderKind::Shared));
+        create_folder(&mut contract, "x_team", "alice", Some(FolderKind::Shared));
+        share_folder(&mut contract, "team", "bob_x", Permission::Read);
+        share_folder(&mut contract, "x_team", "bob", Permission::Write);
+        assert_eq!(contract.get_share_count("team".to_string()), 1);
+        assert_eq!(contract.get_share_count("x_team".to_string()), 1);
+        assert!(!contract.can_write("team".to_string(), "bob_x".to_string()));
+        assert!(contract.can_write("x_team".to_string(), "bob".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "folder_id can't eq file_id")]
+    fn folder_id_cannot_reuse_a_file_id() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "notes");
+        create_folder(&mut contract, "notes", "team", None);
+    }
+
+    #[test]
+    #[should_panic(expected = "file_id can't eq folder_id")]
+    fn file_id_cannot_reuse_a_folder_id() {
+        let mut contract = setup_team();
+        create_folder(&mut contract, "plans", "team", None);
+        create_file(&mut contract, "team", "plans");
+    }
+
+    #[test]
+    #[should_panic(expected = "Parent folder not found: 'nowhere'")]
+    fn missing_parent_is_not_a_permission_error() {
+        let mut contract = setup_team();
+        as_account("carol");
+        create_folder(&mut contract, "plans", "nowhere", None);
+    }
+
+    fn create_file_with(contract: &mut Contract, cid: &str, name: &str, file_type: &str) {
+        as_account("alice");
+        contract.create_file_v2(
+            "team".to_string(),
+            "notes".to_string(),
+            cid.to_string(),
+            name.to_string(),
+            None,
+            file_type.to_string(),
+            10,
+            1,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "cid can't be empty")]
+    fn file_cid_cannot_be_empty() {
+        let mut contract = setup_team();
+        create_file_with(&mut contract, "", "notes", "text/plain");
+    }
+
+    #[test]
+    #[should_panic(expected = "name can't be empty")]
+    fn file_name_cannot_be_empty() {
+        let mut contract = setup_team();
+        create_file_with(&mut contract, "notes-cid", "", "text/plain");
+    }
+
+    #[test]
+    #[should_panic(expected = "file_type can't be empty")]
+    fn file_type_cannot_be_empty() {
+        let mut contract = setup_team();
+        create_file_with(&mut contract, "notes-cid", "notes", "");
+    }
+
+    #[test]
+    #[should_panic(expected = "name can't be empty")]
+    fn folder_name_cannot_be_empty() {
+        let mut contract = setup_team();
+        contract.create_folder_v2(
+            "plans".to_string(),
+            String::new(),
+            "team".to_string(),
+            None,
+            None,
+            1,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn can_write_follows_the_share_permission() {
+        let mut contract = setup_team();
+        create_folder(&mut contract, "plans", "team", None);
+        share_folder(&mut contract, "team", "erin", Permission::Manage);
+        for folder in &["team", "plans"] {
+            let can_write =
+                |account: &str| contract.can_write(folder.to_string(), account.to_string());
+            assert!(can_write("alice"));
+            assert!(can_write("bob"));
+            assert!(can_write("erin"));
+            assert!(!can_write("carol"));
+            assert!(!can_write("dave"));
+        }
+        assert!(!contract.can_write("nowhere".to_string(), "alice".to_string()));
+    }
+
+    #[test]
+    fn re_register_keeps_the_drive() {
+        let mut contract = setup(&["alice"]);
+        as_account("alice");
+        create_folder(&mut contract, "docs", "alice", None);
+        as_account("alice");
+        contract.re_register("new-key".to_string(), "new-token".to_string(), 1);
+        assert_eq!(
+            contract.get_own_user().unwrap().encrypted_token,
+            "new-token"
+        );
+        assert_eq!(contract.get_children("alice".to_string(), 0, 10).len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "User not found: 'eve'")]
+    fn re_register_needs_an_account() {
+        let mut contract = setup(&["alice"]);
+        as_account("alice");
+        create_folder(&mut contract, "eve", "alice", None);
+        as_account("eve");
+        contract.re_register("eve-key".to_string(), "eve-token".to_string(), 1);
+    }
+
+    #[test]
+    fn legacy_share_permissions_fall_back_to_read() {
+        for (stored, permission) in &[
+            (1u8, Permission::Read),
+            (2, Permission::Write),
+            (3, Permission::Read),
+            (5, Permission::Read),
+        ] {
+            let bytes = (
+                "team".to_string(),
+                "share-password".to_string(),
+                *stored,
+                1u64,
+                u8::from(DocType::Folder),
+            )
+                .try_to_vec()
+                .unwrap();
+            let share = ShareDoc::try_from_slice(&bytes).unwrap();
+            assert_eq!(share.permission, *permission);
+            assert_eq!(share.doc_type, DocType::Folder);
+        }
+    }
+
+    #[test]
+    fn current_shares_reject_unknown_permissions() {
+        let mut bytes = (
+            "team".to_string(),
+            "share-password".to_string(),
+            5u8,
+            1u64,
+            u8::from(DocType::Folder),
+        )
+            .try_to_vec()
+            .unwrap();
+        bytes.extend(
+            (None::<u64>, "bob".to_string(), "alice".to_string())
+                .try_to_vec()
+                .unwrap(),
+        );
+        assert!(ShareDoc::try_from_slice(&bytes).is_err());
+    }
+
+    #[test]
+    fn unknown_legacy_folder_types_read_as_none() {
+        for (stored, folder_type) in &[
+            (Some(1u8), Some(FolderKind::Common)),
+            (Some(2), Some(FolderKind::Shared)),
+            (Some(7), None),
+            (None, None),
+        ] {
+            let bytes = (
+                "team".to_string(),
+                Vec::<String>::new(),
+                "alice".to_string(),
+                Vec::<String>::new(),
+                *stored,
+                None::<String>,
+                "alice".to_string(),
+                1u64,
+            )
+                .try_to_vec()
+                .unwrap();
+            let folder = FolderV2::try_from_slice(&bytes).unwrap();
+            assert_eq!(folder.folder_type, *folder_type);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Only an owner can manage the owners of plans")]
+    fn moving_to_the_top_drops_the_creator_as_owner() {
+        let mut contract = setup_team();
+        as_account("bob");
+        create_folder(&mut contract, "plans", "team", None);
+        as_account("alice");
+        contract.move_folder("plans".to_string(), "alice".to_string());
+        assert_eq!(
+            contract
+                .folders_v2
+                .get(&"plans".to_string())
+                .unwrap()
+                .owners,
+            vec!["alice".to_string()]
+        );
+        as_account("bob");
+        contract.add_owner("plans".to_string(), "mallory".to_string());
+    }
+
+    #[test]
+    fn only_top_level_folders_list_owners() {
+        let mut contract = setup_team();
+        as_account("bob");
+        create_folder(&mut contract, "plans", "team", None);
+        let owners = |contract: &Contract, id: &str| {
+            contract.folders_v2.get(&id.to_string()).unwrap().owners
+        };
+        assert_eq!(owners(&contract, "team"), vec!["alice".to_string()]);
+        assert!(owners(&contract, "plans").is_empty());
+    }
+
+    fn setup_custom_root() -> Contract {
+        let mut contract = setup(&["bob"]);
+        as_account("alice");
+        contract.sign_up(
+            "alice-key".to_string(),
+            "alice-token".to_string(),
+            1,
+            Some("alice-drive".to_string()),
+        );
+        create_folder(&mut contract, "team", "alice-drive", None);
+        contract
+    }
+
+    #[test]
+    fn custom_root_takes_folders_moved_to_the_top() {
+        let mut contract = setup_custom_root();
+        create_folder(&mut contract, "plans", "team", None);
+        contract.move_folder("plans".to_string(), "alice-drive".to_string());
+        assert_eq!(
+            contract
+                .get_folder_info_v2("plans".to_string())
+                .unwrap()
+                .parent,
+            "alice-drive"
+        );
+        assert_eq!(
+            contract.owner_of_root("plans".to_string()),
+            Some("alice".to_string())
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The primary owner can't be removed")]
+    fn custom_root_keeps_its_primary_owner() {
+        let mut contract = setup_custom_root();
+        contract.add_owner("team".to_string(), "bob".to_string());
+        as_account("bob");
+        contract.remove_owner("team".to_string(), "alice".to_string());
+    }
+
+    fn setup_trashed_plans() -> Contract {
+        let mut contract = setup_team();
+        create_folder(&mut contract, "plans", "team", None);
+        create_folder(&mut contract, "q3", "plans", None);
+        as_account("alice");
+        contract.remove_folder_v2("plans".to_string());
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = "You don't have permission to change this folder")]
+    fn cannot_create_a_file_in_a_trashed_folder() {
+        let mut contract = setup_trashed_plans();
+        as_account("bob");
+        create_file(&mut contract, "plans", "notes");
+    }
+
+    #[test]
+    #[should_panic(expected = "You don't have permission to change this folder")]
+    fn cannot_create_a_folder_below_a_trashed_folder() {
+        let mut contract = setup_trashed_plans();
+        as_account("alice");
+        create_folder(&mut contract, "drafts", "q3", None);
+    }
+
+    #[test]
+    fn restored_folders_are_writable_again() {
+        let mut contract = setup_trashed_plans();
+        assert!(!contract.can_write("q3".to_string(), "bob".to_string()));
+        as_account("alice");
+        contract.restore_item("plans".to_string());
+        assert!(contract.can_write("q3".to_string(), "bob".to_string()));
+        as_account("bob");
+        create_file(&mut contract, "q3", "notes");
+    }
+
+    #[test]
+    fn delete_account_leaves_no_records() {
+        let mut contract = setup_team();
+        as_account("bob");
+        create_file(&mut contract, "team", "draft");
+        as_account("bob");
+        contract.add_favorite("team".to_string());
+        as_account("bob");
+        let token = contract.create_public_link(
+            "draft".to_string(),
+            "link-password".to_string(),
+            Permission::Read,
+            None,
+            Some("bob-request".to_string()),
+        );
+        assert!(contract.resolve_public_link(token.clone()).is_some());
+
+        as_account("bob");
+        while !contract.delete_account() {}
+        let bob = "bob".to_string();
+        assert!(contract.resolve_public_link(token).is_none());
+        assert!(contract.links_of_user.get(&bob).is_none());
+        assert!(contract.get_favorites(bob.clone()).is_empty());
+        assert!(contract.seen_requests.get(&bob).is_none());
+        assert!(contract.stats.get(&bob).is_none());
+        assert!(contract.usage.get(&bob).is_none());
+        assert!(contract.storage_used.get(&bob).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Must attach")]
+    fn file_versions_are_paid_for() {
+        let mut contract = setup_team();
+        create_file(&mut contract, "team", "notes");
+        as_account_with("alice", 0);
+        contract.update_file(
+            "team".to_string(),
+            "notes".to_string(),
+            "notes-cid-2".to_string(),
+            2,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Must attach")]
+    fn favorites_are_paid_for() {
+        let mut contract = setup_team();
+        as_account_with("bob", 0);
+        contract.add_favorite("team".to_string());
+    }
+
+    #[test]
+    fn renames_and_moves_are_recorded() {
+        let mut contract = setup_team();
+        as_account("bob");
+        create_folder(&mut contract, "plans", "team", None);
+        create_file(&mut contract, "team", "notes");
+        contract.rename_file("team".to_string(), "notes".to_string(), "Notes".to_string());
+        contract.move_file("notes".to_string(), "team".to_string(), "plans".to_string());
+        contract.rename_folder("plans".to_string(), "Plans".to_string());
+        as_account("alice");
+        contract.move_folder("plans".to_string(), "alice".to_string());
+
+        let actions = |account: &str| -> Vec<(String, String)> {
+            contract
+                .get_activity(account.to_string(), 0, 100)
+                .into_iter()
+                .map(|entry| (entry.action, entry.target))
+                .collect()
+        };
+        let bob = actions("bob");
+        assert!(bob.contains(&("file_renamed".to_string(), "notes".to_string())));
+        assert!(bob.contains(&("file_moved".to_string(), "notes".to_string())));
+        assert!(bob.contains(&("folder_renamed".to_string(), "plans".to_string())));
+        assert_eq!(
+            actions("alice").last(),
+            Some(&("folder_moved".to_string(), "plans".to_string()))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Must attach")]
+    fn renames_pay_for_their_activity_entry() {
+        let mut contract = setup_team();
+        create_folder(&mut contract, "plans", "team", None);
+        as_account_with("bob", 0);
+        contract.rename_folder("plans".to_string(), "Plans".to_string());
+    }
+}